@@ -0,0 +1,179 @@
+extern crate rustbox;
+
+#[cfg(test)]
+use std;
+use self::rustbox::RustBox;
+
+// a grid of cells to draw on. The colors and styles of the cells are the ones
+// of termbox (e.g. rustbox::Color::Red.as_16color() | rustbox::RB_BOLD.bits()).
+// Cells out of the canvas are ignored.
+pub trait Canvas {
+    fn change_cell(&mut self, x: usize, y: usize, ch: u32, fg: u16, bg: u16);
+
+    fn clear(&mut self);
+
+    // shows what has been drawn since the last call
+    fn present(&mut self);
+
+    // one cell per character, from x to the right
+    fn print(&mut self, x: usize, y: usize, text: &str, fg: u16, bg: u16) {
+        for (i, ch) in text.chars().enumerate() {
+            self.change_cell(x + i, y, ch as u32, fg, bg);
+        }
+    }
+}
+
+impl Canvas for RustBox {
+    fn change_cell(&mut self, x: usize, y: usize, ch: u32, fg: u16, bg: u16) {
+        unsafe {
+            RustBox::change_cell(self, x, y, ch, fg, bg);
+        }
+    }
+
+    fn clear(&mut self) {
+        RustBox::clear(self);
+    }
+
+    fn present(&mut self) {
+        RustBox::present(self);
+    }
+}
+
+#[cfg(test)]
+#[derive(Clone, Copy, PartialEq)]
+pub struct Cell {
+    pub ch: u32,
+    pub fg: u16,
+    pub bg: u16,
+}
+
+#[cfg(test)]
+const EMPTY_CELL: Cell = Cell { ch: ' ' as u32, fg: 0, bg: 0 };
+
+// a canvas kept in memory, to check what is drawn in the tests
+#[cfg(test)]
+pub struct CellGrid {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,
+}
+
+#[cfg(test)]
+impl CellGrid {
+    pub fn new(width: usize, height: usize) -> Self {
+        CellGrid {
+            width: width,
+            height: height,
+            cells: vec![EMPTY_CELL; width * height],
+        }
+    }
+
+    pub fn get_cell(&self, x: usize, y: usize) -> Option<Cell> {
+        if (x < self.width) && (y < self.height) {
+            Some(self.cells[y * self.width + x])
+        } else {
+            None
+        }
+    }
+
+    // the characters of the cells, one line per row, without the trailing empty
+    // cells. The colors and styles are given as ansi escape codes if asked for.
+    pub fn to_text(&self, with_colors: bool) -> String {
+        let mut res = String::new();
+        for y in 0..self.height {
+            let line_end = (0..self.width)
+                .rposition(|x| self.get_cell(x, y).unwrap() != EMPTY_CELL)
+                .map_or(0, |x| x + 1);
+
+            let mut last_colors = (0, 0);
+            for x in 0..line_end {
+                let cell = self.get_cell(x, y).unwrap();
+                if with_colors && ((cell.fg, cell.bg) != last_colors) {
+                    res.push_str(&get_ansi_escape_code(cell.fg, cell.bg));
+                    last_colors = (cell.fg, cell.bg);
+                }
+                res.push(std::char::from_u32(cell.ch).unwrap_or('?'));
+            }
+
+            if with_colors && (last_colors != (0, 0)) {
+                res.push_str(&get_ansi_escape_code(0, 0));
+            }
+            res.push('\n');
+        }
+        res
+    }
+}
+
+// termbox colors go from 1 (black) to 8 (white), 0 being the default one
+#[cfg(test)]
+fn get_ansi_escape_code(fg: u16, bg: u16) -> String {
+    let mut codes = vec!["0".to_owned()];
+    if (fg & 0x0100) != 0 {
+        codes.push("1".to_owned()); // bold
+    }
+    if (fg & 0x0400) != 0 {
+        codes.push("7".to_owned()); // reverse
+    }
+    match fg & 0x00FF {
+        c @ 1..=8 => codes.push(format!("{}", 29 + c)),
+        _ => (),
+    }
+    match bg & 0x00FF {
+        c @ 1..=8 => codes.push(format!("{}", 39 + c)),
+        _ => (),
+    }
+    format!("\x1b[{}m", codes.join(";"))
+}
+
+#[cfg(test)]
+impl Canvas for CellGrid {
+    fn change_cell(&mut self, x: usize, y: usize, ch: u32, fg: u16, bg: u16) {
+        if (x < self.width) && (y < self.height) {
+            self.cells[y * self.width + x] = Cell { ch: ch, fg: fg, bg: bg };
+        }
+    }
+
+    fn clear(&mut self) {
+        for cell in self.cells.iter_mut() {
+            *cell = EMPTY_CELL;
+        }
+    }
+
+    fn present(&mut self) {}
+}
+
+// shows the columns of a drawing from first_column, on nb_columns columns of a
+// canvas starting at x. The cells out of them are ignored.
+pub struct Viewport<'a> {
+    canvas: &'a mut Canvas,
+    x: usize,
+    first_column: usize,
+    nb_columns: usize,
+}
+
+impl<'a> Viewport<'a> {
+    pub fn new(canvas: &'a mut Canvas, x: usize, first_column: usize, nb_columns: usize) -> Self {
+        Viewport {
+            canvas: canvas,
+            x: x,
+            first_column: first_column,
+            nb_columns: nb_columns,
+        }
+    }
+}
+
+impl<'a> Canvas for Viewport<'a> {
+    fn change_cell(&mut self, x: usize, y: usize, ch: u32, fg: u16, bg: u16) {
+        if (x >= self.first_column) && (x < self.first_column + self.nb_columns) {
+            self.canvas.change_cell(self.x + x - self.first_column, y, ch, fg, bg);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.canvas.clear();
+    }
+
+    fn present(&mut self) {
+        self.canvas.present();
+    }
+}
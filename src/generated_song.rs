@@ -0,0 +1,125 @@
+// generates midi files of any size, for the tests and the benchmarks. The
+// songs are made of chords on several channels, played legato so that keys are
+// often released and pressed again at the same time.
+use std;
+use std::io::Cursor;
+use keyboard_events_extractor;
+use midi_reader::{read_midi_songs, MetaEvent, MidiEvent, MidiMetaEvent, MidiSong, ParseOptions};
+use midi_writer::{write_midi_song, SmfFormat};
+use tempo_map::{TempoMap, TimeDivision};
+use utils;
+
+const TICKDIV: u16 = 480;
+const NB_CHANNELS: u64 = 4;
+
+// xorshift, so that the songs are the same from one run to the other
+struct Random {
+    state: u64,
+}
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        Random { state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1 }
+    }
+
+    fn next(&mut self, max: u64) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state % max
+    }
+}
+
+// format 1 midi data holding about nb_events midi events, and a tempo change
+// every 16 beats
+pub fn generate_midi_file(nb_events: usize, seed: u64) -> Vec<u8> {
+    let mut random = Random::new(seed);
+    let mut events: Vec<(u64, Vec<u8>)> = Vec::new();
+
+    for channel in 0..NB_CHANNELS {
+        let mut ticks: u64 = 0;
+        while events.len() < nb_events * (channel as usize + 1) / NB_CHANNELS as usize {
+            // a few close pitches, so that the same keys come back often
+            let duration = (1 + random.next(4)) * TICKDIV as u64 / 2;
+            let nb_keys = 1 + random.next(3) as usize;
+            let mut chord: Vec<u8> = Vec::new();
+            while chord.len() < nb_keys {
+                let pitch = 48 + 12 * channel as u8 + random.next(8) as u8;
+                if !chord.contains(&pitch) {
+                    chord.push(pitch);
+                }
+            }
+            for &pitch in chord.iter() {
+                events.push((ticks, vec![0x90 | channel as u8, pitch, 1 + random.next(127) as u8]));
+            }
+            for &pitch in chord.iter() {
+                // a note on of velocity 0 releases the key too
+                let status = if random.next(2) == 0 { 0x80 } else { 0x90 };
+                events.push((ticks + duration, vec![status | channel as u8, pitch, 0]));
+            }
+            ticks += duration;
+        }
+    }
+
+    // the sort is stable: the keys released and pressed at the same time stay
+    // in that order
+    events.sort_by_key(|x| x.0);
+    let song_end = events.last().map_or(0, |x| x.0);
+
+    let mut tempo_map = TempoMap::new(TimeDivision::TicksPerQuarterNote(TICKDIV));
+    let mut meta_events: Vec<MidiMetaEvent> = Vec::new();
+    for ticks in (0..song_end + 1).filter(|x| x % (16 * TICKDIV as u64) == 0) {
+        let us_per_quarter_note = 300_000 + 50_000 * random.next(8) as u32;
+        tempo_map.add_tempo_change(ticks, us_per_quarter_note);
+        meta_events.push(MidiMetaEvent {
+                             ticks: ticks,
+                             time: tempo_map.get_time_in_ns(ticks),
+                             event: MetaEvent::Tempo(us_per_quarter_note),
+                         });
+    }
+
+    let midi_events: Vec<MidiEvent> = events.into_iter()
+        .map(|(ticks, data)| {
+                 MidiEvent {
+                     ticks: ticks,
+                     time: tempo_map.get_time_in_ns(ticks),
+                     track: 0,
+                     data: data,
+                 }
+             })
+        .collect();
+
+    let mut res: Vec<u8> = Vec::new();
+    write_midi_song(&mut res, &midi_events, &meta_events, &tempo_map, SmfFormat::MultipleTrack).unwrap();
+    res
+}
+
+pub fn read_generated_song(data: Vec<u8>) -> MidiSong {
+    let options = ParseOptions { lenient: false, report_unknown_chunks: false };
+    let mut warnings = Vec::new();
+    read_midi_songs(&mut Cursor::new(data), options, &mut warnings).unwrap().remove(0)
+}
+
+fn get_elapsed_ms(start: std::time::Instant) -> u64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() * 1_000 + elapsed.subsec_nanos() as u64 / 1_000_000
+}
+
+// cargo test --release benchmark -- --ignored --nocapture
+#[test]
+#[ignore]
+fn benchmark_500k_events() {
+    let data = generate_midi_file(500_000, 1);
+
+    let start = std::time::Instant::now();
+    let song = read_generated_song(data);
+    println!("reading {} events: {} ms", song.events.len(), get_elapsed_ms(start));
+
+    let start = std::time::Instant::now();
+    let key_events = keyboard_events_extractor::get_key_events(&song.events).unwrap();
+    println!("extracting {} key events: {} ms", key_events.len(), get_elapsed_ms(start));
+
+    let start = std::time::Instant::now();
+    let music_events = utils::group_events_by_time(&song.events, &key_events, &song.meta_events).unwrap();
+    println!("grouping into {} music events: {} ms", music_events.len(), get_elapsed_ms(start));
+}
@@ -1,18 +1,26 @@
 use std::cmp;
+use std::collections::{HashMap, VecDeque};
 use midi_reader;
 
 #[derive(Clone, Copy)]
 pub enum KeyData {
-    Pressed(u8), // u8 is the pitch
+    Pressed(u8, u8), // the pitch and the velocity
     Released(u8),
 }
 
 #[derive(Clone, Copy)]
 pub struct KeyEvent {
     pub data: KeyData,
+    pub channel: u8, // midi channel, from 0 to 15
+    pub track: u16, // index of the track the key event comes from
     pub time_in_ns: u64,
 }
 
+// index of a key in tables holding one element per channel and pitch
+fn get_key_index(channel: u8, pitch: u8) -> usize {
+    channel as usize * 256 + pitch as usize
+}
+
 fn separate_released_pressed_keys(keyboard_events: &mut Vec<KeyEvent>) -> Result<(), String> {
     // precond the events MUST be sorted by time. this function only works on that case
     for i in 1..keyboard_events.len() {
@@ -25,46 +33,67 @@ fn separate_released_pressed_keys(keyboard_events: &mut Vec<KeyEvent>) -> Result
     // at the exact same time as its associated release event. If so, shorten the
     // duration of the former pressed event (i.e advance the time the release
     // event occurs).
-
-    // suboptimal implementation in the case the key_events are sorted
+    //
+    // Keys are paired per channel: the same pitch can be held by different
+    // instruments at the same time. The tracks are not taken into account since
+    // a release in one track can end a note started in another one.
+    //
+    // The events are handled by groups of events occuring at the same time.
+    // last_pressed_time holds, for each key, the time of its latest pressed
+    // event among the previous groups.
+    let mut last_pressed_time: Vec<Option<u64>> = vec![None; 16 * 256];
     let mut pos_shortening_time: Vec<(usize, u64)> = Vec::new();
-    for k in keyboard_events.iter() {
-        if let KeyData::Pressed(pitch) = k.data {
-            let earliest_time = k.time_in_ns;
-
-            // is there a release happening at the same time?
-            let find_released_pos_fn = |x: &KeyEvent| match (x.time_in_ns, &x.data) {
-                (time, &KeyData::Released(this_pitch)) if (time == earliest_time) &&
-                                                          (this_pitch == pitch) => true,
-                (_, _) => false,
-            };
-
-            if let Some(released_elt_index) =
-                keyboard_events.iter().position(find_released_pos_fn) {
-                // there is a release key happening at the same time.
-                // Let's find the pressed key responsible for it
-                let find_prev_pressed_fn = |x: &&KeyEvent| match (x.time_in_ns, &x.data) {
-                    (time, &KeyData::Pressed(this_pitch)) if (time < earliest_time) &&
-                                                             (this_pitch == pitch) => true,
-                    (_, _) => false,
-                };
 
-                match keyboard_events.iter().rev().find(find_prev_pressed_fn) {
-                    Some(pressed_key_pos) => {
-                        // compute the shortening time
-                        let duration = earliest_time - pressed_key_pos.time_in_ns;
-                        let max_shortening_time_in_ns: u64 = 75_000_000; // 75 ms
+    let nb_events = keyboard_events.len();
+    let mut group_start = 0;
+    while group_start < nb_events {
+        let earliest_time = keyboard_events[group_start].time_in_ns;
+        let group_end = keyboard_events[group_start..]
+            .iter()
+            .position(|x| x.time_in_ns != earliest_time)
+            .map_or(nb_events, |x| group_start + x);
+        let group = &keyboard_events[group_start..group_end];
+
+        // position of the first release event of the group, for each key
+        let mut released_pos: HashMap<usize, usize> = HashMap::new();
+        for (i, k) in group.iter().enumerate() {
+            if let KeyData::Released(pitch) = k.data {
+                let _ = released_pos.entry(get_key_index(k.channel, pitch)).or_insert(group_start + i);
+            }
+        }
+
+        for k in group {
+            if let KeyData::Pressed(pitch, _) = k.data {
+                let key_index = get_key_index(k.channel, pitch);
+
+                // is there a release happening at the same time?
+                if let Some(&released_elt_index) = released_pos.get(&key_index) {
+                    // there is a release key happening at the same time.
+                    // Let's find the pressed key responsible for it
+                    match last_pressed_time[key_index] {
+                        Some(pressed_time) => {
+                            // compute the shortening time
+                            let duration = earliest_time - pressed_time;
+                            let max_shortening_time_in_ns: u64 = 75_000_000; // 75 ms
 
-                        // shorten the duration by one fourth of its time, in the worst case
-                        let shortening_time = cmp::min(max_shortening_time_in_ns, duration / 4);
+                            // shorten the duration by one fourth of its time, in the worst case
+                            let shortening_time = cmp::min(max_shortening_time_in_ns, duration / 4);
 
-                        pos_shortening_time.push((released_elt_index, shortening_time));
+                            pos_shortening_time.push((released_elt_index, shortening_time));
+                        }
+                        None => return Err("error, there is a release event coming from nowhere (failed to find the associated pressed event)".to_owned()),
                     }
-                    None => return Err("error, there is a release event coming from nowhere (failed to find the associated pressed event)".to_owned()),
                 }
             }
+        }
 
+        for k in group {
+            if let KeyData::Pressed(pitch, _) = k.data {
+                last_pressed_time[get_key_index(k.channel, pitch)] = Some(earliest_time);
+            }
         }
+
+        group_start = group_end;
     }
 
     for &(pos, shortening_time) in pos_shortening_time.iter() {
@@ -74,7 +103,8 @@ fn separate_released_pressed_keys(keyboard_events: &mut Vec<KeyEvent>) -> Result
     return Ok(());
 }
 
-pub fn get_key_events(midi_events: &Vec<midi_reader::MidiEvent>) -> Result<Vec<KeyEvent>, String> {
+// the key pressed and released events, in the order of the midi events
+fn to_key_events(midi_events: &Vec<midi_reader::MidiEvent>) -> Vec<KeyEvent> {
     // pre condition, events must be sorted!
     for i in 1..midi_events.len() {
         if midi_events[i].time < midi_events[i - 1].time {
@@ -88,13 +118,17 @@ pub fn get_key_events(midi_events: &Vec<midi_reader::MidiEvent>) -> Result<Vec<K
         if ev.is_key_released() {
             res.push(KeyEvent {
                          data: KeyData::Released(ev.get_pitch().unwrap()),
+                         channel: ev.get_channel(),
+                         track: ev.track,
                          time_in_ns: ev.time,
                      })
         }
 
         if ev.is_key_pressed() {
             res.push(KeyEvent {
-                         data: KeyData::Pressed(ev.get_pitch().unwrap()),
+                         data: KeyData::Pressed(ev.get_pitch().unwrap(), ev.get_velocity().unwrap()),
+                         channel: ev.get_channel(),
+                         track: ev.track,
                          time_in_ns: ev.time,
                      })
         }
@@ -106,6 +140,12 @@ pub fn get_key_events(midi_events: &Vec<midi_reader::MidiEvent>) -> Result<Vec<K
         }
     }
 
+    return res;
+}
+
+pub fn get_key_events(midi_events: &Vec<midi_reader::MidiEvent>) -> Result<Vec<KeyEvent>, String> {
+    let mut res = to_key_events(midi_events);
+
     // pre condition, res must be sorted!
     for i in 1..res.len() {
         if res[i].time_in_ns < res[i - 1].time_in_ns {
@@ -117,3 +157,214 @@ pub fn get_key_events(midi_events: &Vec<midi_reader::MidiEvent>) -> Result<Vec<K
 
     return Ok(res);
 }
+
+// keeps the notes whose pressed event is accepted by is_shown, along with the
+// events releasing them, whatever their track. The events are paired as in
+// get_notes_from_key_events, and the releases without a pressed key are kept
+// if they are accepted.
+pub fn retain_notes<F>(key_events: &mut Vec<KeyEvent>, is_shown: F)
+    where F: Fn(&KeyEvent) -> bool
+{
+    // whether the notes being held are shown, for each channel and pitch
+    let mut held_notes: Vec<VecDeque<bool>> = (0..16 * 256).map(|_| VecDeque::new()).collect();
+
+    let mut is_kept: Vec<bool> = Vec::with_capacity(key_events.len());
+    for k_ev in key_events.iter() {
+        match k_ev.data {
+            KeyData::Pressed(pitch, _) => {
+                let shown = is_shown(k_ev);
+                held_notes[get_key_index(k_ev.channel, pitch)].push_back(shown);
+                is_kept.push(shown);
+            }
+            KeyData::Released(pitch) => {
+                let shown = held_notes[get_key_index(k_ev.channel, pitch)].pop_front().unwrap_or_else(|| is_shown(k_ev));
+                is_kept.push(shown);
+            }
+        }
+    }
+
+    let mut is_kept = is_kept.into_iter();
+    key_events.retain(|_| is_kept.next().unwrap_or(false));
+}
+
+// a key held from start_ns to end_ns
+pub struct Note {
+    pub start_ns: u64,
+    pub end_ns: u64,
+    pub pitch: u8,
+    pub velocity: u8,
+    pub channel: u8,
+    #[allow(dead_code)] // not drawn by the piano roll
+    pub track: u16,
+}
+
+// the key events must be sorted by time. The notes still held at the end are
+// ended at song_end.
+pub fn get_notes_from_key_events(key_events: &[KeyEvent], song_end: u64) -> Vec<Note> {
+    let mut res: Vec<Note> = Vec::new();
+
+    // indexes in res of the notes being held, for each channel and pitch
+    let mut held_notes: Vec<VecDeque<usize>> = (0..16 * 256).map(|_| VecDeque::new()).collect();
+
+    for k_ev in key_events {
+        match k_ev.data {
+            KeyData::Pressed(pitch, velocity) => {
+                held_notes[get_key_index(k_ev.channel, pitch)].push_back(res.len());
+                res.push(Note {
+                             start_ns: k_ev.time_in_ns,
+                             end_ns: k_ev.time_in_ns,
+                             pitch: pitch,
+                             velocity: velocity,
+                             channel: k_ev.channel,
+                             track: k_ev.track,
+                         });
+            }
+            KeyData::Released(pitch) => {
+                if let Some(pos) = held_notes[get_key_index(k_ev.channel, pitch)].pop_front() {
+                    res[pos].end_ns = k_ev.time_in_ns;
+                }
+            }
+        }
+    }
+
+    for pos in held_notes.iter().flat_map(|x| x.iter()) {
+        res[*pos].end_ns = song_end;
+    }
+
+    return res;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use generated_song::{generate_midi_file, read_generated_song};
+    use midi_reader::{read_midi_songs, ParseOptions};
+    use utils;
+
+    // separate_released_pressed_keys as it was before handling the events by
+    // groups, which looked the events up in the whole song for each pressed key
+    fn separate_released_pressed_keys_reference(keyboard_events: &mut Vec<KeyEvent>) -> Result<(), String> {
+        let mut pos_shortening_time: Vec<(usize, u64)> = Vec::new();
+        for k in keyboard_events.iter() {
+            if let KeyData::Pressed(pitch, _) = k.data {
+                let earliest_time = k.time_in_ns;
+                let channel = k.channel;
+
+                let find_released_pos_fn = |x: &KeyEvent| match (x.time_in_ns, &x.data) {
+                    (time, &KeyData::Released(this_pitch)) if (time == earliest_time) &&
+                                                              (this_pitch == pitch) &&
+                                                              (x.channel == channel) => true,
+                    (_, _) => false,
+                };
+
+                if let Some(released_elt_index) = keyboard_events.iter().position(find_released_pos_fn) {
+                    let find_prev_pressed_fn = |x: &&KeyEvent| match (x.time_in_ns, &x.data) {
+                        (time, &KeyData::Pressed(this_pitch, _)) if (time < earliest_time) &&
+                                                                 (this_pitch == pitch) &&
+                                                                 (x.channel == channel) => true,
+                        (_, _) => false,
+                    };
+
+                    match keyboard_events.iter().rev().find(find_prev_pressed_fn) {
+                        Some(pressed_key_pos) => {
+                            let duration = earliest_time - pressed_key_pos.time_in_ns;
+                            let shortening_time = cmp::min(75_000_000, duration / 4);
+                            pos_shortening_time.push((released_elt_index, shortening_time));
+                        }
+                        None => return Err("release event coming from nowhere".to_owned()),
+                    }
+                }
+            }
+        }
+
+        for &(pos, shortening_time) in pos_shortening_time.iter() {
+            keyboard_events[pos].time_in_ns -= shortening_time;
+        }
+
+        Ok(())
+    }
+
+    fn get_key_data(key_events: &[KeyEvent]) -> Vec<(u64, u8, u16, bool, u8, u8)> {
+        key_events.iter()
+            .map(|x| match x.data {
+                     KeyData::Pressed(pitch, velocity) => (x.time_in_ns, x.channel, x.track, true, pitch, velocity),
+                     KeyData::Released(pitch) => (x.time_in_ns, x.channel, x.track, false, pitch, 0),
+                 })
+            .collect()
+    }
+
+    #[test]
+    fn separates_the_keys_as_the_reference() {
+        for seed in 1..6 {
+            let song = read_generated_song(generate_midi_file(5_000, seed));
+            let unseparated = to_key_events(&song.events);
+            let mut expected = unseparated.clone();
+            separate_released_pressed_keys_reference(&mut expected).unwrap();
+
+            let key_events = get_key_events(&song.events).unwrap();
+            assert_eq!(get_key_data(&key_events), get_key_data(&expected));
+
+            // the song does have keys released and pressed at the same time
+            let nb_shortened = unseparated.iter().zip(key_events.iter()).filter(|&(a, b)| a.time_in_ns != b.time_in_ns).count();
+            assert!(nb_shortened > 100, "only {} keys shortened", nb_shortened);
+        }
+    }
+
+    #[test]
+    fn keeps_the_notes_released_by_another_track() {
+        // the note 60 starts in the first track and ends in the second one
+        let mut data = b"MThd".to_vec();
+        data.extend_from_slice(&[0, 0, 0, 6, 0, 1, 0, 2, 0, 96]);
+        data.extend_from_slice(b"MTrk");
+        data.extend_from_slice(&[0, 0, 0, 16, 0x00, 0x90, 60, 100, 0x00, 0x90, 64, 100, 0x60, 0x80, 64, 0, 0x00, 0xFF, 0x2F, 0x00]);
+        data.extend_from_slice(b"MTrk");
+        data.extend_from_slice(&[0, 0, 0, 16, 0x60, 0x80, 60, 0, 0x00, 0x90, 62, 100, 0x60, 0x80, 62, 0, 0x00, 0xFF, 0x2F, 0x00]);
+
+        let options = ParseOptions { lenient: false, report_unknown_chunks: false };
+        let song = read_midi_songs(&mut Cursor::new(data), options, &mut Vec::new()).unwrap().remove(0);
+
+        let get_shown_keys = |track: u16| {
+            let mut key_events = get_key_events(&song.events).unwrap();
+            retain_notes(&mut key_events, |x| x.track == track);
+            assert!(utils::group_events_by_time(&song.events, &key_events, &song.meta_events).is_ok());
+            get_key_data(&key_events).into_iter().map(|x| (x.0, x.2, x.3, x.4)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(get_shown_keys(0),
+                   vec![(0, 0, true, 60), (0, 0, true, 64), (500_000_000, 0, false, 64), (500_000_000, 1, false, 60)]);
+        assert_eq!(get_shown_keys(1), vec![(500_000_000, 1, true, 62), (1_000_000_000, 1, false, 62)]);
+    }
+
+    fn key_event(time_in_ns: u64, channel: u8, track: u16, data: KeyData) -> KeyEvent {
+        KeyEvent {
+            data: data,
+            channel: channel,
+            track: track,
+            time_in_ns: time_in_ns,
+        }
+    }
+
+    #[test]
+    fn pairs_overlapping_notes_in_the_order_they_started() {
+        // the same key pressed twice on the first channel before being
+        // released twice, and once on the second channel
+        let key_events = vec![key_event(0, 0, 0, KeyData::Pressed(60, 10)),
+                              key_event(10, 0, 1, KeyData::Pressed(60, 20)),
+                              key_event(15, 1, 0, KeyData::Pressed(60, 30)),
+                              key_event(20, 0, 1, KeyData::Released(60)),
+                              key_event(30, 0, 0, KeyData::Released(60)),
+                              key_event(35, 0, 0, KeyData::Pressed(62, 40)),
+                              key_event(40, 0, 0, KeyData::Released(61))];
+
+        let notes: Vec<(u64, u64, u8, u8, u8, u16)> = get_notes_from_key_events(&key_events, 100)
+            .iter()
+            .map(|x| (x.start_ns, x.end_ns, x.pitch, x.velocity, x.channel, x.track))
+            .collect();
+
+        // the notes still held are ended with the song, and the release
+        // without a pressed key is ignored
+        assert_eq!(notes,
+                   vec![(0, 20, 60, 10, 0, 0), (10, 30, 60, 20, 0, 1), (15, 100, 60, 30, 1, 0), (35, 100, 62, 40, 0, 0)]);
+    }
+}
@@ -5,16 +5,127 @@ use std::error::Error;
 
 mod ports_printer;
 mod midi_reader;
+mod midi_writer;
+mod tempo_map;
 mod keyboard_events_extractor;
 mod utils;
 mod music_player;
 mod signal_handler;
+mod scheduler;
+mod canvas;
+#[cfg(test)]
+mod generated_song;
+
+// reads the midi file, and keeps only the selected song if song_number is given.
+// Exits on error.
+fn get_midi_songs(filename: &str,
+                  parse_options: midi_reader::ParseOptions,
+                  song_number: Option<&str>)
+                  -> Vec<midi_reader::MidiSong> {
+    let mut warnings = Vec::new();
+    let midi_songs = midi_reader::get_midi_songs(filename, parse_options, &mut warnings).unwrap_or_else(|e| {
+        println!("Error occured while reading {}: {}", filename, e);
+        std::process::exit(2)
+    });
+
+    for warning in warnings {
+        println!("Warning: {}", warning);
+    }
+
+    match song_number {
+        Some(value) => {
+            match usize::from_str(value) {
+                Ok(v) if v < midi_songs.len() => midi_songs.into_iter().skip(v).take(1).collect(),
+                Ok(v) => {
+                    println!("Error: invalid song number given. Got {} but the file only contains {} song(s)", v, midi_songs.len());
+                    std::process::exit(2)
+                }
+                Err(e) => {
+                    println!("Error: invalid song number given. {}", e.description());
+                    std::process::exit(2)
+                }
+            }
+        }
+        None => midi_songs,
+    }
+}
+
+// parses a comma separated list of numbers, each one lower than max. Exits on error.
+fn parse_number_list(value: &str, max: u32, what: &str) -> Vec<u32> {
+    value.split(',')
+        .map(|x| match u32::from_str(x.trim()) {
+                 Ok(v) if v < max => v,
+                 Ok(v) => {
+                     println!("Error: invalid {} given. Got {} but the maximum is {}", what, v, max - 1);
+                     std::process::exit(2)
+                 }
+                 Err(e) => {
+                     println!("Error: invalid {} given. {}", what, e.description());
+                     std::process::exit(2)
+                 }
+             })
+        .collect()
+}
+
+// parses the output port number. Exits on error.
+fn get_output_port(value: Option<&str>) -> u32 {
+    match value {
+        Some(value) => {
+            match u32::from_str(value) {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("Error: invalid output port given.{}\n. Below is the list of possible output ports", e.description());
+                    ports_printer::print_outputs();
+                    std::process::exit(2)
+                }
+            }
+        }
+        None => {
+            println!("Error: an output port must be given. Below is the list of possible output ports");
+            ports_printer::print_outputs();
+            std::process::exit(2)
+        }
+    }
+}
+
+// parses a START-END range. Exits on error.
+fn parse_range<T>(value: &str, what: &str) -> (T, T)
+    where T: FromStr,
+          T::Err: Error
+{
+    let bounds: Vec<&str> = value.splitn(2, '-').collect();
+    if bounds.len() != 2 {
+        println!("Error: invalid {} given. Expected START-END but got {}", what, value);
+        std::process::exit(2)
+    }
+
+    let parse_bound = |x: &str| {
+        T::from_str(x.trim()).unwrap_or_else(|e| {
+            println!("Error: invalid {} given. {}", what, e.description());
+            std::process::exit(2)
+        })
+    };
+    (parse_bound(bounds[0]), parse_bound(bounds[1]))
+}
 
 fn main() {
     let input_midi_port_option_name = "input port";
     let input_midi_file_option_name = "input midi file";
     let output_midi_port_option_name = "output port";
     let list_option_name = "list";
+    let song_option_name = "song";
+    let lenient_option_name = "lenient";
+    let verbose_option_name = "verbose";
+    let export_option_name = "export";
+    let export_format_option_name = "export format";
+    let record_option_name = "record";
+    let channels_option_name = "channels";
+    let tracks_option_name = "tracks";
+    let pedal_color_option_name = "pedal color";
+    let speed_option_name = "speed";
+    let loop_option_name = "loop";
+    let loop_bars_option_name = "loop bars";
+    let loop_speed_up_option_name = "loop speed up";
 
     let options = clap::App::new("pianoterm-rs")
         .version("0.1")
@@ -34,13 +145,92 @@ fn main() {
                  .takes_value(true)
                  .value_name("OUTPUT_PORT_NUM")
                  .help("The midi output port to send music to")
-                 .required_unless(list_option_name))
+                 .required_unless_one(&[list_option_name, export_option_name]))
         .arg(clap::Arg::with_name(list_option_name)
                  .short("l")
                  .long("list")
                  .takes_value(false)
                  .help("lists the available midi port"))
+        .arg(clap::Arg::with_name(song_option_name)
+                 .short("s")
+                 .long("song")
+                 .takes_value(true)
+                 .value_name("SONG_NUMBER")
+                 .help("The song to play out of a multiple song midi file (first one is 0). All songs are played back-to-back if not given")
+                 .conflicts_with(input_midi_port_option_name))
+        .arg(clap::Arg::with_name(lenient_option_name)
+                 .long("lenient")
+                 .takes_value(false)
+                 .help("Tries to play slightly broken midi files instead of rejecting them")
+                 .conflicts_with(input_midi_port_option_name))
+        .arg(clap::Arg::with_name(verbose_option_name)
+                 .short("v")
+                 .long("verbose")
+                 .takes_value(false)
+                 .help("Reports the unknown chunks skipped while reading the midi file")
+                 .conflicts_with(input_midi_port_option_name))
+        .arg(clap::Arg::with_name(export_option_name)
+                 .short("e")
+                 .long("export")
+                 .takes_value(true)
+                 .value_name("OUTPUT_MIDI_FILE")
+                 .help("Writes the song to a standard midi file instead of playing it")
+                 .conflicts_with(input_midi_port_option_name))
+        .arg(clap::Arg::with_name(export_format_option_name)
+                 .long("export-format")
+                 .takes_value(true)
+                 .possible_values(&["0", "1"])
+                 .help("The format of the exported midi file: 0 (single track) or 1 (multiple track)")
+                 .requires(export_option_name))
+        .arg(clap::Arg::with_name(record_option_name)
+                 .short("r")
+                 .long("record")
+                 .takes_value(true)
+                 .value_name("OUTPUT_MIDI_FILE")
+                 .help("Saves what is played on the midi input port into a midi file")
+                 .requires(input_midi_port_option_name))
+        .arg(clap::Arg::with_name(channels_option_name)
+                 .long("channels")
+                 .takes_value(true)
+                 .value_name("CHANNELS")
+                 .help("Only shows the keys played on these midi channels (from 0 to 15, comma separated). All channels are still heard")
+                 .conflicts_with(input_midi_port_option_name))
+        .arg(clap::Arg::with_name(tracks_option_name)
+                 .long("tracks")
+                 .takes_value(true)
+                 .value_name("TRACKS")
+                 .help("Only shows the keys played by these tracks (first one is 0, comma separated). All tracks are still heard")
+                 .conflicts_with(input_midi_port_option_name))
+        .arg(clap::Arg::with_name(pedal_color_option_name)
+                 .long("pedal-color")
+                 .takes_value(false)
+                 .help("Draws the keys only held by the sustain or sostenuto pedal in yellow"))
+        .arg(clap::Arg::with_name(speed_option_name)
+                 .long("speed")
+                 .takes_value(true)
+                 .value_name("PERCENT")
+                 .help("The playback speed, from 25 to 200 percent. Use + and - to change it while playing")
+                 .conflicts_with(input_midi_port_option_name))
+        .arg(clap::Arg::with_name(loop_option_name)
+                 .long("loop")
+                 .takes_value(true)
+                 .value_name("START-END")
+                 .help("Repeats the section of the song between these positions, in seconds (e.g. 12.5-30)")
+                 .conflicts_with_all(&[input_midi_port_option_name, loop_bars_option_name]))
+        .arg(clap::Arg::with_name(loop_bars_option_name)
+                 .long("loop-bars")
+                 .takes_value(true)
+                 .value_name("FIRST-LAST")
+                 .help("Repeats these bars of the song (first one is 1, e.g. 5-8)")
+                 .conflicts_with(input_midi_port_option_name))
+        .arg(clap::Arg::with_name(loop_speed_up_option_name)
+                 .long("loop-speed-up")
+                 .takes_value(true)
+                 .value_name("PERCENT")
+                 .help("Adds this percentage to the playback speed each time the loop starts again")
+                 .conflicts_with(input_midi_port_option_name))
         .arg(clap::Arg::with_name(input_midi_file_option_name)
+                 .help("The midi file to play (use - to read it from the standard input)")
                  .required_unless_one(&[list_option_name, input_midi_port_option_name]))
         .get_matches();
 
@@ -51,48 +241,122 @@ fn main() {
         return;
     }
 
-    let port = match options.value_of(output_midi_port_option_name) {
-        Some(value) => {
-            match u32::from_str(value) {
-                Ok(v) => v,
-                Err(e) => {
-                    println!("Error: invalid output port given.{}\n. Below is the list of possible output ports", e.description());
-                    ports_printer::print_outputs();
-                    std::process::exit(2)
-                }
-            }
+    let parse_options = midi_reader::ParseOptions {
+        lenient: options.is_present(lenient_option_name),
+        report_unknown_chunks: options.is_present(verbose_option_name),
+    };
+
+    if let Some(output_filename) = options.value_of(export_option_name) {
+        let filename = options.value_of(input_midi_file_option_name).unwrap_or_else(|| {
+            println!("Error: a midi file must be given to export it");
+            std::process::exit(2)
+        });
+
+        let midi_songs = get_midi_songs(filename, parse_options, options.value_of(song_option_name));
+        if midi_songs.len() != 1 {
+            println!("Error: the midi file contains {} songs. Select the one to export using --song", midi_songs.len());
+            std::process::exit(2)
         }
-        None => {
-            println!("Error: an output port must be given. Below is the list of possible output ports");
-            ports_printer::print_outputs();
+
+        let format = match options.value_of(export_format_option_name) {
+            Some("0") => midi_writer::SmfFormat::SingleTrack,
+            _ => midi_writer::SmfFormat::MultipleTrack,
+        };
+
+        let song = &midi_songs[0];
+        if let Err(e) = midi_writer::save_midi_song(output_filename, &song.events, &song.meta_events, &song.tempo_map, format) {
+            println!("Error occured while exporting the song: {}", e);
             std::process::exit(2)
         }
-    };
+        return;
+    }
 
     signal_handler::register_signal_listener();
 
     match options.value_of(input_midi_file_option_name) {
         Some(filename) => {
-            let midi_events = midi_reader::get_midi_events(filename).unwrap_or_else(|e| {
-                println!("Error occured: {}", e);
-                std::process::exit(2)
-            });
+            let midi_songs = get_midi_songs(filename, parse_options, options.value_of(song_option_name));
+            let shown_channels = options.value_of(channels_option_name).map(|x| parse_number_list(x, 16, "channel"));
+            let shown_tracks = options.value_of(tracks_option_name).map(|x| parse_number_list(x, 0x1_0000, "track"));
+            let speed_percent = match options.value_of(speed_option_name).map(u32::from_str) {
+                Some(Ok(v)) if (v >= music_player::MIN_SPEED_PERCENT) && (v <= music_player::MAX_SPEED_PERCENT) => v,
+                Some(Ok(v)) => {
+                    println!("Error: invalid speed given. Got {} but it must be between {} and {}",
+                             v, music_player::MIN_SPEED_PERCENT, music_player::MAX_SPEED_PERCENT);
+                    std::process::exit(2)
+                }
+                Some(Err(e)) => {
+                    println!("Error: invalid speed given. {}", e.description());
+                    std::process::exit(2)
+                }
+                None => 100,
+            };
+
+            let loop_section = match (options.value_of(loop_option_name), options.value_of(loop_bars_option_name)) {
+                (Some(value), _) => {
+                    let (start, end): (f64, f64) = parse_range(value, "loop");
+                    if !((start >= 0.0) && (start < end) && end.is_finite()) {
+                        println!("Error: invalid loop given. Got {} but the start must be positive and before the end", value);
+                        std::process::exit(2)
+                    }
+                    Some(music_player::LoopSection::Time {
+                        start_in_ns: (start * 1_000_000_000.0) as u64,
+                        end_in_ns: (end * 1_000_000_000.0) as u64,
+                    })
+                }
+                (None, Some(value)) => {
+                    let (first, last): (u64, u64) = parse_range(value, "loop bars");
+                    if !((first >= 1) && (first <= last)) {
+                        println!("Error: invalid loop bars given. Got {} but the first bar is 1, and must not be after the last one", value);
+                        std::process::exit(2)
+                    }
+                    Some(music_player::LoopSection::Bars { first_bar: first - 1, last_bar: last - 1 })
+                }
+                (None, None) => None,
+            };
+
+            let loop_speed_up_percent = match options.value_of(loop_speed_up_option_name).map(u32::from_str) {
+                Some(Ok(v)) => v,
+                Some(Err(e)) => {
+                    println!("Error: invalid loop speed up given. {}", e.description());
+                    std::process::exit(2)
+                }
+                None => 0,
+            };
+
+            let play_options = music_player::PlayOptions {
+                show_pedal_held_keys: options.is_present(pedal_color_option_name),
+                speed_percent: speed_percent,
+                loop_section: loop_section,
+                loop_speed_up_percent: loop_speed_up_percent,
+            };
 
-            let keyboard_events = keyboard_events_extractor::get_key_events(&midi_events)
-                .unwrap_or_else(|e| {
-                                    println!("Error occured: {}", e);
-                                    std::process::exit(2)
-                                });
+            let mut songs: Vec<(utils::Song, tempo_map::TempoMap)> = Vec::new();
+            for midi_song in midi_songs {
+                let mut keyboard_events = keyboard_events_extractor::get_key_events(&midi_song.events)
+                    .unwrap_or_else(|e| {
+                                        println!("Error occured: {}", e);
+                                        std::process::exit(2)
+                                    });
 
-            println!("extracted {} keyboard events", keyboard_events.len());
+                // hidden notes are removed as a whole (both the pressed and the released events),
+                // a note released by another track being shown with the track it started in
+                keyboard_events_extractor::retain_notes(&mut keyboard_events, |x| {
+                    shown_channels.as_ref().map_or(true, |channels| channels.contains(&(x.channel as u32))) &&
+                    shown_tracks.as_ref().map_or(true, |tracks| tracks.contains(&(x.track as u32)))
+                });
 
-            let song = utils::group_events_by_time(&midi_events, &keyboard_events).unwrap_or_else(|e| {
-                println!("Error occured while grouping events occuring at the same time: {}", e);
-                std::process::exit(2);
-            });
+                println!("extracted {} keyboard events", keyboard_events.len());
 
+                let song = utils::group_events_by_time(&midi_song.events, &keyboard_events, &midi_song.meta_events).unwrap_or_else(|e| {
+                    println!("Error occured while grouping events occuring at the same time: {}", e);
+                    std::process::exit(2);
+                });
+
+                songs.push((song, midi_song.tempo_map));
+            }
 
-            music_player::play(song, port);
+            music_player::play(songs, get_output_port(options.value_of(output_midi_port_option_name)), play_options);
         }
         None => {
             println!("listening to input port for midi events");
@@ -111,7 +375,10 @@ fn main() {
                 },
             };
 
-            music_player::play_midi_input(input_midi_port, port);
+            music_player::play_midi_input(input_midi_port,
+                                          get_output_port(options.value_of(output_midi_port_option_name)),
+                                          options.value_of(record_option_name),
+                                          options.is_present(pedal_color_option_name));
         }
     }
 }
@@ -1,10 +1,171 @@
 extern crate byteorder;
-use midi_reader::byteorder::{ReadBytesExt, BigEndian};
+use midi_reader::byteorder::{ReadBytesExt, BigEndian, LittleEndian};
 
 use std::io::prelude::*;
 use std::error::Error;
 use std::io::SeekFrom;
+use std::fmt;
 use std;
+use tempo_map::{TempoMap, TimeDivision};
+
+#[derive(Debug)]
+pub enum MidiParseErrorKind {
+    Io(std::io::Error),
+    InvalidMagicNumber,
+    InvalidHeaderSize(u32),
+    InvalidMidiType(u16),
+    InvalidTrackCount(u16), // a single track file must contain exactly one track
+    NullTimeDivision,
+    InvalidFramesPerSecond(u8),
+    InvalidTrackHeader,
+    UnknownChunk([u8; 4]), // the chunk type
+    MissingEndOfTrack,
+    InvalidVariableLength(usize), // number of bytes used
+    UnknownEvent(u8),
+    ForbiddenTempoEvent,
+    InvalidTrackLength { declared: u32, actual: u64 },
+    InvalidMetaEvent { meta_type: u8, size: usize },
+    ExtraBytes,
+    NoSong,
+}
+
+#[derive(Debug)]
+pub struct MidiParseError {
+    pub kind: MidiParseErrorKind,
+    pub offset: Option<u64>, // position in the input where the error was detected
+    pub track: Option<u16>,
+    pub event: Option<usize>, // index of the event within its track
+}
+
+impl MidiParseError {
+    // adds the position within the track to errors which don't have one yet
+    fn in_track(self, track: u16, event: Option<usize>) -> Self {
+        MidiParseError {
+            track: self.track.or(Some(track)),
+            event: self.event.or(event),
+            ..self
+        }
+    }
+
+    // true if the error comes from reaching the end of the input too early
+    fn is_unexpected_end_of_input(&self) -> bool {
+        match self.kind {
+            MidiParseErrorKind::Io(ref e) => e.kind() == std::io::ErrorKind::UnexpectedEof,
+            _ => false,
+        }
+    }
+
+    // adds the offset to errors which don't have one yet
+    fn at_offset(self, offset: u64) -> Self {
+        MidiParseError {
+            offset: self.offset.or(Some(offset)),
+            ..self
+        }
+    }
+}
+
+impl From<MidiParseErrorKind> for MidiParseError {
+    fn from(kind: MidiParseErrorKind) -> Self {
+        MidiParseError {
+            kind: kind,
+            offset: None,
+            track: None,
+            event: None,
+        }
+    }
+}
+
+impl fmt::Display for MidiParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MidiParseErrorKind::Io(ref e) => write!(f, "Failed to read the midi data: {}", e),
+            MidiParseErrorKind::InvalidMagicNumber => write!(f, "The midi data doesn't start by the correct header"),
+            MidiParseErrorKind::InvalidHeaderSize(x) => write!(f, "Invalid header size. Expecting 6, got {}", x),
+            MidiParseErrorKind::InvalidMidiType(x) => {
+                write!(f, "Invalid midi file type. Expecting either 0 (single track), 1 (multiple track) or 2 (multiple song), got {}", x)
+            }
+            MidiParseErrorKind::InvalidTrackCount(x) => {
+                write!(f, "Midi file is supposed to be a single track one but it says it contains {} tracks", x)
+            }
+            MidiParseErrorKind::NullTimeDivision => {
+                write!(f, "A quarter note is made of 0 pulses (which is impossible) according to the midi data")
+            }
+            MidiParseErrorKind::InvalidFramesPerSecond(x) => {
+                write!(f, "Invalid number of frames per second. Expecting either 24, 25, 29 or 30, got {}", x)
+            }
+            MidiParseErrorKind::InvalidTrackHeader => write!(f, "Couldn't read the track header"),
+            MidiParseErrorKind::UnknownChunk(chunk_type) => {
+                let chunk_type: String = chunk_type.iter()
+                    .flat_map(|x| std::ascii::escape_default(*x))
+                    .map(|x| x as char)
+                    .collect();
+                write!(f, "Skipped an unknown chunk of type \"{}\"", chunk_type)
+            }
+            MidiParseErrorKind::MissingEndOfTrack => write!(f, "A track doesn't end with an end of track event"),
+            MidiParseErrorKind::InvalidVariableLength(x) => {
+                write!(f, "Invalid variable length value. Maximum size allowed is 4 bytes. Bytes used: {}", x)
+            }
+            MidiParseErrorKind::UnknownEvent(x) => write!(f, "Unknown Midi event 0x{:02X}", x),
+            MidiParseErrorKind::ForbiddenTempoEvent => write!(f, "A tempo event found at a forbidden place"),
+            MidiParseErrorKind::InvalidTrackLength { declared, actual } => {
+                write!(f, "Invalid track length detected. Expecting {} bytes, got {}", declared, actual)
+            }
+            MidiParseErrorKind::InvalidMetaEvent { meta_type, size } => {
+                write!(f, "META event 0x{:02X} has an invalid size ({} bytes)", meta_type, size)
+            }
+            MidiParseErrorKind::ExtraBytes => write!(f, "The midi data contains extra bytes after the last track"),
+            MidiParseErrorKind::NoSong => write!(f, "The midi data doesn't contain any song"),
+        }
+    }
+}
+
+impl fmt::Display for MidiParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(track) = self.track {
+            write!(f, " (track {}", track)?;
+            if let Some(event) = self.event {
+                write!(f, ", event {}", event)?;
+            }
+            write!(f, ")")?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at byte offset {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for MidiParseError {
+    fn description(&self) -> &str {
+        "invalid midi data"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match self.kind {
+            MidiParseErrorKind::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct ParseOptions {
+    // accept slightly broken midi data, reporting the problems found as warnings
+    pub lenient: bool,
+    // report the chunks of unknown types, which are always skipped, as warnings
+    pub report_unknown_chunks: bool,
+}
+
+// creates an error located at the current position in the file
+fn error_at<R: Seek>(file: &mut R, kind: MidiParseErrorKind) -> MidiParseError {
+    MidiParseError {
+        kind: kind,
+        offset: file.seek(SeekFrom::Current(0)).ok(),
+        track: None,
+        event: None,
+    }
+}
 
 fn is_header_correct(actual_buffer: [u8; 4], expected: [u8; 4]) -> bool {
     for i in 0..4 {
@@ -15,80 +176,118 @@ fn is_header_correct(actual_buffer: [u8; 4], expected: [u8; 4]) -> bool {
     true
 }
 
-fn read_magic_number(file: &mut std::fs::File, filename: &str) -> Result<(), String> {
+// skips the RIFF RMID container (.rmi files) wrapping some midi data, up to
+// the beginning of its data chunk. Returns the position where the data chunk
+// ends.
+fn skip_riff_container<R: Read + Seek>(file: &mut R) -> Result<u64, MidiParseError> {
+    //    riff_chunk = "RIFF" + <length> + "RMID" + <sub_chunk> [+ <sub_chunk> ...]
+    //    sub_chunk = <type> + <length> + <data> [+ <padding byte>]
+    //
+    // lengths are 4 bytes little endian values. A padding byte follows data
+    // whose length is odd. The midi data is held in the sub chunk of type "data".
+    //
+    // "RIFF" has already been read.
+
+    if let Err(e) = file.read_u32::<LittleEndian>() /* length of the RIFF chunk */ {
+        return Err(error_at(file, MidiParseErrorKind::Io(e)));
+    }
+
+    let mut form_buffer: [u8; 4] = [0; 4];
+    if let Err(e) = file.read_exact(&mut form_buffer) {
+        return Err(error_at(file, MidiParseErrorKind::Io(e)));
+    }
+
+    let rmid_header: [u8; 4] = ['R' as u8, 'M' as u8, 'I' as u8, 'D' as u8];
+    if !is_header_correct(form_buffer, rmid_header) {
+        return Err(error_at(file, MidiParseErrorKind::InvalidMagicNumber));
+    }
+
+    let data_header: [u8; 4] = ['d' as u8, 'a' as u8, 't' as u8, 'a' as u8];
+    loop {
+        let mut chunk_buffer: [u8; 4] = [0; 4];
+        if let Err(e) = file.read_exact(&mut chunk_buffer) {
+            return Err(error_at(file, MidiParseErrorKind::Io(e)));
+        }
+
+        let chunk_length: u32 = match file.read_u32::<LittleEndian>() {
+            Err(e) => return Err(error_at(file, MidiParseErrorKind::Io(e))),
+            Ok(v) => v,
+        };
+
+        if is_header_correct(chunk_buffer, data_header) {
+            return match file.seek(SeekFrom::Current(0)) {
+                Err(e) => Err(error_at(file, MidiParseErrorKind::Io(e))),
+                Ok(v) => Ok(v + chunk_length as u64),
+            };
+        }
+
+        let padding = (chunk_length & 1) as i64;
+        if let Err(e) = file.seek(SeekFrom::Current(chunk_length as i64 + padding)) {
+            return Err(error_at(file, MidiParseErrorKind::Io(e)));
+        }
+    }
+}
+
+// reads the magic number starting the midi data. Midi data wrapped into a RIFF
+// RMID container is transparently unwrapped, in which case the position where
+// the midi data ends within the container is returned.
+fn read_magic_number<R: Read + Seek>(file: &mut R) -> Result<Option<u64>, MidiParseError> {
     let mut header_buffer: [u8; 4] = [0; 4];
     if let Err(e) = file.read_exact(&mut header_buffer) {
-        return Err(format!("Failed to read the header out of file {}: {}",
-                           filename,
-                           e.description()));
+        return Err(error_at(file, MidiParseErrorKind::Io(e)));
+    }
+
+    let mut data_end = None;
+    let riff_header: [u8; 4] = ['R' as u8, 'I' as u8, 'F' as u8, 'F' as u8];
+    if is_header_correct(header_buffer, riff_header) {
+        data_end = Some(skip_riff_container(file)?);
+
+        if let Err(e) = file.read_exact(&mut header_buffer) {
+            return Err(error_at(file, MidiParseErrorKind::Io(e)));
+        }
     }
 
     let midi_header: [u8; 4] = ['M' as u8, 'T' as u8, 'h' as u8, 'd' as u8];
     if !is_header_correct(header_buffer, midi_header) {
-        return Err(format!("The file {} doesn't start by the correct header", filename));
+        return Err(error_at(file, MidiParseErrorKind::InvalidMagicNumber));
     }
 
-    Ok(())
+    Ok(data_end)
 }
 
-fn read_header_size(file: &mut std::fs::File, filename: &str) -> Result<(), String> {
+fn read_header_size<R: Read + Seek>(file: &mut R) -> Result<(), MidiParseError> {
     match file.read_u32::<BigEndian>() {
         Ok(6) => Ok(()),
-        Ok(x) => {
-            Err(format!("Invalid header size found in file {}. Expecting 6, got {}",
-                        filename,
-                        x))
-        }
-        Err(e) => {
-            Err(format!("Failed to read header size out of file {}: {}",
-                        filename,
-                        e.description()))
-        }
+        Ok(x) => Err(error_at(file, MidiParseErrorKind::InvalidHeaderSize(x))),
+        Err(e) => Err(error_at(file, MidiParseErrorKind::Io(e))),
     }
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy)]
 enum MidiType {
     SingleTrack = 0,
     MultipleTrack = 1,
     MultipleSong = 2, // i.e. a series of type 0
 }
 
-fn read_midi_type(file: &mut std::fs::File, filename: &str) -> Result<MidiType, String> {
+fn read_midi_type<R: Read + Seek>(file: &mut R) -> Result<MidiType, MidiParseError> {
     match file.read_u16::<BigEndian>() {
         Ok(0) => Ok(MidiType::SingleTrack),
         Ok(1) => Ok(MidiType::MultipleTrack),
         Ok(2) => Ok(MidiType::MultipleSong),
-        Ok(e) => {
-            Err(format!("Invalid midi file type for file {}. Expecting either 0 (single track), 1 (multiple track) or 2 (multiple song), got {}",
-                        filename,
-                        e))
-        }
-        Err(e) => {
-            Err(format!("Failed to read the midi type of {}: {}",
-                        filename,
-                        e.description()))
-        }
+        Ok(x) => Err(error_at(file, MidiParseErrorKind::InvalidMidiType(x))),
+        Err(e) => Err(error_at(file, MidiParseErrorKind::Io(e))),
     }
 }
 
-fn read_nb_tracks(file: &mut std::fs::File, filename: &str) -> Result<u16, String> {
+fn read_nb_tracks<R: Read + Seek>(file: &mut R) -> Result<u16, MidiParseError> {
     match file.read_u16::<BigEndian>() {
-        Ok(e) => Ok(e),
-        Err(e) => {
-            Err(format!("Failed to read the number of tracks of file {}: {}",
-                        filename,
-                        e.description()))
-        }
+        Ok(x) => Ok(x),
+        Err(e) => Err(error_at(file, MidiParseErrorKind::Io(e))),
     }
 }
 
-enum TempoStyle {
-    MetricalTiming,
-    Timecode,
-}
-
-fn get_tickdiv(file: &mut std::fs::File, filename: &str) -> Result<(u16, TempoStyle), String> {
+fn get_time_division<R: Read + Seek>(file: &mut R) -> Result<TimeDivision, MidiParseError> {
     // http://midi.mathewvp.com/aboutMidi.htm
 
     // The last two bytes indicate how many Pulses (i.e. clocks) Per Quarter Note
@@ -105,39 +304,47 @@ fn get_tickdiv(file: &mut std::fs::File, filename: &str) -> Result<(u16, TempoSt
     let mut bytes: [u8; 2] = [0; 2];
 
     if let Err(e) = file.read_exact(&mut bytes) {
-        return Err(format!("Failed to read data from file {}: {}",
-                           filename,
-                           e.description()));
+        return Err(error_at(file, MidiParseErrorKind::Io(e)));
     }
 
     if (bytes[0] as i8) >= 0 {
         let tickdiv: u16 = ((bytes[0] as u16) << 8) | (bytes[1] as u16);
-        return Ok((tickdiv, TempoStyle::MetricalTiming));
+        if tickdiv == 0 {
+            return Err(error_at(file, MidiParseErrorKind::NullTimeDivision));
+        }
+        return Ok(TimeDivision::TicksPerQuarterNote(tickdiv));
     } else {
         let frames_per_sec: u8 = (-(bytes[0] as i8)) as u8;
-        match frames_per_sec {
-            24 | 25 | 29 | 30 => {
-                let resolution: u8 = bytes[1];
-                let res: u16 = resolution as u16 * frames_per_sec as u16;
-                Ok((res, TempoStyle::Timecode))
-            }
-            _ => Err("Error: midi file contain an invalid number of frames per second".to_owned()),
+        let resolution: u8 = bytes[1];
+        let (ticks_per_second, drop_frame) = match frames_per_sec {
+            24 | 25 | 30 => (resolution as u16 * frames_per_sec as u16, false),
+            // 29 stands for 30 drop frame, i.e. 29.97 frames per second.
+            29 => (resolution as u16 * 30, true),
+            x => return Err(error_at(file, MidiParseErrorKind::InvalidFramesPerSecond(x))),
+        };
+        if ticks_per_second == 0 {
+            return Err(error_at(file, MidiParseErrorKind::NullTimeDivision));
         }
+        return Ok(TimeDivision::Timecode {
+                      ticks_per_second: ticks_per_second,
+                      ticks_per_frame: resolution,
+                      drop_frame: drop_frame,
+                  });
     }
 }
 
-fn read_one_byte(file: &mut std::fs::File) -> Result<u8, std::io::Error> {
+fn read_one_byte<R: Read>(file: &mut R) -> Result<u8, std::io::Error> {
     let mut buffer: [u8; 1] = [0; 1];
     file.read_exact(&mut buffer)?;
     return Ok(buffer[0]);
 }
 
-fn get_variable_length_array(mut file: &mut std::fs::File) -> Result<Vec<u8>, String> {
+fn get_variable_length_array<R: Read + Seek>(mut file: &mut R) -> Result<Vec<u8>, MidiParseError> {
     let mut res = Vec::<u8>::new();
 
     loop {
         match read_one_byte(&mut file) {
-            Err(e) => return Err(format!("Failed to read byte: {}", e.description())),
+            Err(e) => return Err(error_at(file, MidiParseErrorKind::Io(e))),
             Ok(v) => {
                 res.push(v);
                 if (v & 0x80) == 0 {
@@ -151,9 +358,9 @@ fn get_variable_length_array(mut file: &mut std::fs::File) -> Result<Vec<u8>, St
     Ok(res)
 }
 
-fn get_variable_length_value(vec: &[u8]) -> Result<u64, String> {
+fn get_variable_length_value(vec: &[u8]) -> Result<u64, MidiParseError> {
     if vec.len() > 8 {
-        return Err("buffer is too big to extract a variable length value from".to_owned());
+        return Err(MidiParseError::from(MidiParseErrorKind::InvalidVariableLength(vec.len())));
     }
 
     let mut res: u64 = 0;
@@ -166,13 +373,12 @@ fn get_variable_length_value(vec: &[u8]) -> Result<u64, String> {
 
 // reads a variable length value. BUT it must be four bytes maximum.
 // otherwise it is not valid.
-fn get_relative_time(mut file: &mut std::fs::File) -> Result<u32, String> {
+fn get_relative_time<R: Read + Seek>(mut file: &mut R) -> Result<u32, MidiParseError> {
     // recreate the right value by removing the continuation bits
     let buffer = get_variable_length_array(&mut file)?;
 
     if buffer.len() > 4 {
-        return Err(format!("Invalid relative timing found.\nMaximum size allowed is 4 bytes. Bytes used: {}",
-                           buffer.len()));
+        return Err(error_at(file, MidiParseErrorKind::InvalidVariableLength(buffer.len())));
     }
 
     let res = get_variable_length_value(&buffer)?;
@@ -182,7 +388,9 @@ fn get_relative_time(mut file: &mut std::fs::File) -> Result<u32, String> {
 
 
 pub struct MidiEvent {
-    pub time: u64,
+    pub ticks: u64, // position in the song, in midi ticks
+    pub time: u64, // position in the song, in nanoseconds
+    pub track: u16, // index of the track the event comes from
     pub data: Vec<u8>,
 }
 
@@ -197,41 +405,195 @@ impl MidiEvent {
                 (((self.data[0] & 0xF0) == 0x90) && (self.data[2] == 0x00)));
     }
 
+    // only meaningful for channel events
+    pub fn get_channel(&self) -> u8 {
+        self.data[0] & 0x0F
+    }
+
     pub fn get_pitch(&self) -> Option<u8> {
         match self.data.len() {
             x if x >= 2 => Some(self.data[1]),
             _ => None,
         }
     }
+
+    pub fn get_velocity(&self) -> Option<u8> {
+        match self.data.len() {
+            x if x >= 3 => Some(self.data[2]),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum MetaEvent {
+    SequenceNumber(u16),
+    Text(String),
+    Copyright(String),
+    TrackName(String),
+    InstrumentName(String),
+    Lyric(String),
+    Marker(String),
+    CuePoint(String),
+    ChannelPrefix(u8),
+    EndOfTrack,
+    Tempo(u32), // microseconds per quarter note
+    SmpteOffset {
+        hours: u8,
+        minutes: u8,
+        seconds: u8,
+        frames: u8,
+        subframes: u8,
+    },
+    TimeSignature {
+        numerator: u8,
+        denominator: u16,
+        clocks_per_click: u8,
+        notated_32nd_per_quarter: u8,
+    },
+    KeySignature {
+        sharps: i8, // negative values are flats
+        minor: bool,
+    },
+    SequencerSpecific(Vec<u8>),
+    Unknown(u8, Vec<u8>), // the meta type and its data
+}
+
+pub struct MidiMetaEvent {
+    pub ticks: u64,
+    pub time: u64,
+    pub event: MetaEvent,
+}
+
+impl fmt::Display for MetaEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MetaEvent::SequenceNumber(n) => write!(f, "sequence number: {}", n),
+            MetaEvent::Text(ref text) => write!(f, "{}", text),
+            MetaEvent::Copyright(ref text) => write!(f, "copyright: {}", text),
+            MetaEvent::TrackName(ref text) => write!(f, "{}", text),
+            MetaEvent::InstrumentName(ref text) => write!(f, "instrument: {}", text),
+            MetaEvent::Lyric(ref text) => write!(f, "{}", text),
+            MetaEvent::Marker(ref text) => write!(f, "[{}]", text),
+            MetaEvent::CuePoint(ref text) => write!(f, "cue: {}", text),
+            MetaEvent::ChannelPrefix(channel) => write!(f, "channel prefix: {}", channel),
+            MetaEvent::EndOfTrack => write!(f, "end of track"),
+            MetaEvent::Tempo(us_per_quarter_note) => {
+                write!(f, "tempo: {} bpm", 60_000_000 / std::cmp::max(us_per_quarter_note, 1))
+            }
+            MetaEvent::SmpteOffset { hours, minutes, seconds, frames, subframes } => {
+                write!(f, "smpte offset: {:02}:{:02}:{:02}:{:02}.{:02}", hours, minutes, seconds, frames, subframes)
+            }
+            MetaEvent::TimeSignature { numerator, denominator, clocks_per_click, notated_32nd_per_quarter } => {
+                write!(f, "time signature: {}/{} ({} clocks per click, {} 32nd notes per quarter note)",
+                       numerator, denominator, clocks_per_click, notated_32nd_per_quarter)
+            }
+            MetaEvent::KeySignature { sharps, minor } => {
+                let major_keys = ["Cb", "Gb", "Db", "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#"];
+                let minor_keys = ["Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#", "G#", "D#", "A#"];
+                let pos = (std::cmp::max(-7, std::cmp::min(7, sharps)) + 7) as usize;
+                if minor {
+                    write!(f, "key signature: {} minor", minor_keys[pos])
+                } else {
+                    write!(f, "key signature: {} major", major_keys[pos])
+                }
+            }
+            MetaEvent::SequencerSpecific(ref data) => write!(f, "sequencer specific data ({} bytes)", data.len()),
+            MetaEvent::Unknown(meta_type, ref data) => write!(f, "unknown meta event 0x{:02X} ({} bytes)", meta_type, data.len()),
+        }
+    }
+}
+
+// data must be the raw bytes of a META event, that is 0xFF, the meta type,
+// the variable length of the data, and the data itself.
+fn parse_meta_event(data: &[u8]) -> Result<MetaEvent, MidiParseError> {
+    if (data.len() < 3) || (data[0] != 0xFF) {
+        return Err(MidiParseError::from(MidiParseErrorKind::InvalidMetaEvent {
+                                             meta_type: if data.len() > 1 { data[1] } else { 0 },
+                                             size: data.len(),
+                                         }));
+    }
+
+    let meta_type = data[1];
+
+    // skip the variable length array holding the data length
+    let mut data_start = 2;
+    while (data_start < data.len()) && ((data[data_start] & 0x80) != 0) {
+        data_start += 1;
+    }
+    let payload = &data[std::cmp::min(data_start + 1, data.len())..];
+
+    let text = || String::from_utf8_lossy(payload).into_owned();
+
+    // the events which don't have the size of their type are kept as unknown
+    // ones, e.g. the sequence numbers without data standing for the position of
+    // the track in the file. Only a tempo event is required to be valid.
+    let res = match (meta_type, payload.len()) {
+        (0x00, 2) => MetaEvent::SequenceNumber(((payload[0] as u16) << 8) | (payload[1] as u16)),
+        (0x01, _) => MetaEvent::Text(text()),
+        (0x02, _) => MetaEvent::Copyright(text()),
+        (0x03, _) => MetaEvent::TrackName(text()),
+        (0x04, _) => MetaEvent::InstrumentName(text()),
+        (0x05, _) => MetaEvent::Lyric(text()),
+        (0x06, _) => MetaEvent::Marker(text()),
+        (0x07, _) => MetaEvent::CuePoint(text()),
+        (0x20, 1) => MetaEvent::ChannelPrefix(payload[0]),
+        (0x2F, _) => MetaEvent::EndOfTrack,
+        (0x51, 3) => MetaEvent::Tempo(((payload[0] as u32) << 16) | ((payload[1] as u32) << 8) | (payload[2] as u32)),
+        (0x51, size) => {
+            return Err(MidiParseError::from(MidiParseErrorKind::InvalidMetaEvent {
+                                                 meta_type: meta_type,
+                                                 size: size,
+                                             }))
+        }
+        (0x54, 5) => {
+            MetaEvent::SmpteOffset {
+                hours: payload[0],
+                minutes: payload[1],
+                seconds: payload[2],
+                frames: payload[3],
+                subframes: payload[4],
+            }
+        }
+        // the denominator is given as a power of two
+        (0x58, 4) if payload[1] <= 15 => {
+            MetaEvent::TimeSignature {
+                numerator: payload[0],
+                denominator: 1 << payload[1],
+                clocks_per_click: payload[2],
+                notated_32nd_per_quarter: payload[3],
+            }
+        }
+        (0x59, 2) => {
+            MetaEvent::KeySignature {
+                sharps: payload[0] as i8,
+                minor: payload[1] != 0,
+            }
+        }
+        (0x7F, _) => MetaEvent::SequencerSpecific(payload.to_vec()),
+        _ => MetaEvent::Unknown(meta_type, payload.to_vec()),
+    };
+
+    Ok(res)
 }
 
 // return the next byte of the file without extracting it.
-fn peek_byte(mut file: &mut std::fs::File) -> Result<u8, String> {
+fn peek_byte<R: Read + Seek>(mut file: &mut R) -> Result<u8, MidiParseError> {
     match read_one_byte(&mut file) {
-        Err(e) => Err(format!("Failed to read one byte: {}", e.description())),
+        Err(e) => Err(error_at(file, MidiParseErrorKind::Io(e))),
         Ok(x) => {
             match file.seek(SeekFrom::Current(-1)) {
-                Err(e) => {
-                    Err(format!("Failed to change the cursor position within the file {}",
-                                e.description()))
-                }
+                Err(e) => Err(error_at(file, MidiParseErrorKind::Io(e))),
                 Ok(_) => Ok(x),
             }
         }
     }
 }
 
-fn get_event(mut file: &mut std::fs::File, last_status_byte: u8) -> Result<MidiEvent, String> {
-    // get_relative_time returns MIDI tics. These are the number of tics that occured since the former event
-    // if the song uses the metrical timing tempo_style, or since the beginning of the song if it used the timecode
-    // tempo_style. These are *NOT* in a dimension of seconds. Therefore assigning them to res.time which is of type
-    // nanoseconds is wrong here. instead, there should which denote a midi tics that should be used here.
-    // In order not to create too many types, and avoid some memory and copies overhead, the .time field is used here
-    // to stop the midi tics.
-    // There will be a step later on that will transfer these midi tics (wrongly stored as nanoseconds here), into
-    // the real naonseconds value, based on the tempo style. See function set_real_timings.
-
-    let time_in_ns = get_relative_time(&mut file)? as u64;
+// returns the event with its ticks relative to the former event. Its time in
+// nanoseconds is only known once the whole song is read, see extract_song.
+fn get_event<R: Read + Seek>(mut file: &mut R, last_status_byte: u8) -> Result<MidiEvent, MidiParseError> {
+    let delta_ticks = get_relative_time(&mut file)? as u64;
     let mut data: Vec<u8> = Vec::new();
 
     // we need to look at the next byte in the file (there must be at least one).
@@ -242,10 +604,7 @@ fn get_event(mut file: &mut std::fs::File, last_status_byte: u8) -> Result<MidiE
         x if (x & 0x80) == 0 => last_status_byte,
         x => {
             match file.seek(SeekFrom::Current(1)) { // consume the byte in the file
-                Err(e) => {
-                    return Err(format!("Failed to change the cursor position within the file {}",
-                                       e.description()))
-                }
+                Err(e) => return Err(error_at(file, MidiParseErrorKind::Io(e))),
                 Ok(_) => x,
             }
         }
@@ -255,7 +614,7 @@ fn get_event(mut file: &mut std::fs::File, last_status_byte: u8) -> Result<MidiE
     if event_type == 0xFF {
         // This is a META event
         match read_one_byte(&mut file) /* type of META event  */ {
-            Err(e) => return Err(format!("Error while reading META midi event: {}", e.description())),
+            Err(e) => return Err(error_at(file, MidiParseErrorKind::Io(e))),
             Ok(x) => data.push(x),
         };
         // for the rest, a META event is just like a sysex one.
@@ -264,7 +623,7 @@ fn get_event(mut file: &mut std::fs::File, last_status_byte: u8) -> Result<MidiE
     if (event_type == 0xFF) || (event_type == 0xF0) || (event_type == 0xF7) {
         // this is a sysex event, or the end of a META event.
         let length_array = get_variable_length_array(&mut file)?;
-        let length = get_variable_length_value(&length_array)?;
+        let length = get_variable_length_value(&length_array).map_err(|e| error_at(file, e.kind))?;
 
         // Append the length array at the end of res.data
         for byte in length_array {
@@ -274,16 +633,15 @@ fn get_event(mut file: &mut std::fs::File, last_status_byte: u8) -> Result<MidiE
         // the data
         for _ in 0..length {
             match read_one_byte(&mut file) {
-                Err(e) => {
-                    return Err(format!("Error while parsing sysex or end of meta midi type: {}",
-                                       e.description()))
-                }
+                Err(e) => return Err(error_at(file, MidiParseErrorKind::Io(e))),
                 Ok(byte) => data.push(byte),
             }
         }
 
         return Ok(MidiEvent {
-                      time: time_in_ns,
+                      ticks: delta_ticks,
+                      time: 0,
+                      track: 0,
                       data: data,
                   });
     }
@@ -295,44 +653,166 @@ fn get_event(mut file: &mut std::fs::File, last_status_byte: u8) -> Result<MidiE
         {
             // one more byte
             match read_one_byte(&mut file) {
-                Err(e) => {
-                    return Err(format!("Error while parsing a program after change event or a channel aftertouch event: {}",
-                                       e.description()))
-                }
+                Err(e) => return Err(error_at(file, MidiParseErrorKind::Io(e))),
                 Ok(byte) => data.push(byte),
             };
         } else {
             // this is a MIDI channel event (more two bytes)
             for _ in 0..2 {
                 match read_one_byte(&mut file) {
-                    Err(e) => {
-                        return Err(format!("Error while parsing Midi channel event: {}",
-                                           e.description()))
-                    }
+                    Err(e) => return Err(error_at(file, MidiParseErrorKind::Io(e))),
                     Ok(byte) => data.push(byte),
                 };
             }
         }
 
         return Ok(MidiEvent {
-                      time: time_in_ns,
+                      ticks: delta_ticks,
+                      time: 0,
+                      track: 0,
                       data: data,
                   });
     }
 
-    return Err("Invalid Midi file. Unknown Midi event".to_owned());
+    return Err(error_at(file, MidiParseErrorKind::UnknownEvent(event_type)));
+}
+
+
+// true if the input is at its end, or at the beginning of a track chunk.
+// The position in the input is left unchanged.
+fn is_at_track_boundary<R: Read + Seek>(file: &mut R) -> Result<bool, MidiParseError> {
+    let mut next_bytes: Vec<u8> = Vec::new();
+    let nb_read = match file.by_ref().take(4).read_to_end(&mut next_bytes) {
+        Err(e) => return Err(error_at(file, MidiParseErrorKind::Io(e))),
+        Ok(v) => v,
+    };
+
+    if let Err(e) = file.seek(SeekFrom::Current(-(nb_read as i64))) {
+        return Err(error_at(file, MidiParseErrorKind::Io(e)));
+    }
+
+    Ok(next_bytes.is_empty() || (next_bytes == b"MTrk"))
 }
 
+// reads the header of a track chunk and returns the track length.
+//
+// As required by the specification, chunks of unknown types (e.g. vendor
+// specific ones) found before the track are skipped. In lenient mode, chunks
+// whose type isn't made of ascii letters are skipped too.
+fn read_track_header<R: Read + Seek>(file: &mut R,
+                                     track_index: u16,
+                                     options: ParseOptions,
+                                     warnings: &mut Vec<MidiParseError>)
+                                     -> Result<u32, MidiParseError> {
+    loop {
+        let mut track_buffer: [u8; 4] = [0; 4];
+        if let Err(e) = file.read_exact(&mut track_buffer) {
+            return Err(error_at(file, MidiParseErrorKind::Io(e)).in_track(track_index, None));
+        }
+
+        let track_header: [u8; 4] = ['M' as u8, 'T' as u8, 'r' as u8, 'k' as u8];
+        let is_track = is_header_correct(track_buffer, track_header);
+        let is_chunk = track_buffer.iter().all(|x| x.is_ascii_alphanumeric());
+        if !is_chunk && !options.lenient {
+            return Err(error_at(file, MidiParseErrorKind::InvalidTrackHeader).in_track(track_index, None));
+        }
+
+        let chunk_length: u32 = match file.read_u32::<BigEndian>() {
+            Err(e) => return Err(error_at(file, MidiParseErrorKind::Io(e)).in_track(track_index, None)),
+            Ok(v) => v,
+        };
+
+        if is_track {
+            return Ok(chunk_length);
+        }
+
+        if options.report_unknown_chunks || !is_chunk {
+            warnings.push(error_at(file, MidiParseErrorKind::UnknownChunk(track_buffer)).in_track(track_index, None));
+        }
+
+        if let Err(e) = file.seek(SeekFrom::Current(chunk_length as i64)) {
+            return Err(error_at(file, MidiParseErrorKind::Io(e)).in_track(track_index, None));
+        }
+    }
+}
+
+// skips the chunks of unknown types following the last track, up to data_end
+// or the end of the input. Returns true if bytes which aren't part of such a
+// chunk are left, in which case the position in the input is where they start.
+fn skip_trailing_chunks<R: Read + Seek>(file: &mut R,
+                                        data_end: Option<u64>,
+                                        options: ParseOptions,
+                                        warnings: &mut Vec<MidiParseError>)
+                                        -> Result<bool, MidiParseError> {
+    let mut position = match file.seek(SeekFrom::Current(0)) {
+        Err(e) => return Err(error_at(file, MidiParseErrorKind::Io(e))),
+        Ok(v) => v,
+    };
+
+    let end = match data_end {
+        Some(end) => end,
+        None => {
+            let end = match file.seek(SeekFrom::End(0)) {
+                Err(e) => return Err(error_at(file, MidiParseErrorKind::Io(e))),
+                Ok(v) => v,
+            };
+            if let Err(e) = file.seek(SeekFrom::Start(position)) {
+                return Err(error_at(file, MidiParseErrorKind::Io(e)));
+            }
+            end
+        }
+    };
+
+    // an extra track chunk is not skipped: the track count is wrong
+    while position + 8 <= end {
+        let mut chunk_buffer: [u8; 4] = [0; 4];
+        if let Err(e) = file.read_exact(&mut chunk_buffer) {
+            return Err(error_at(file, MidiParseErrorKind::Io(e)));
+        }
+
+        let chunk_length: u32 = match file.read_u32::<BigEndian>() {
+            Err(e) => return Err(error_at(file, MidiParseErrorKind::Io(e))),
+            Ok(v) => v,
+        };
+
+        let chunk_end = position + 8 + chunk_length as u64;
+        let is_chunk = chunk_buffer.iter().all(|x| x.is_ascii_alphanumeric()) && (&chunk_buffer != b"MTrk");
+        if !is_chunk || (chunk_end > end) {
+            if let Err(e) = file.seek(SeekFrom::Start(position)) {
+                return Err(error_at(file, MidiParseErrorKind::Io(e)));
+            }
+            break;
+        }
+
+        if options.report_unknown_chunks {
+            warnings.push(error_at(file, MidiParseErrorKind::UnknownChunk(chunk_buffer)));
+        }
+
+        if let Err(e) = file.seek(SeekFrom::Start(chunk_end)) {
+            return Err(error_at(file, MidiParseErrorKind::Io(e)));
+        }
+        position = chunk_end;
+    }
+
+    Ok(position < end)
+}
 
 // read the midi events from the track and pushes them at the end of res
 //
 // MIDI format 1 (multiple track) can't have tempo event after the first track.
 // call with the last to true when reading track 2+ from a format 1 to ensure
 // validity check.
-fn get_track_events(res: &mut Vec<MidiEvent>,
-                    mut file: &mut std::fs::File,
-                    fail_on_tempo_event: bool)
-                    -> Result<(), String> {
+//
+// In lenient mode, a track missing its end of track event stops where the next
+// track starts, invalid meta events are dropped, and a wrong track length is
+// only reported in warnings.
+fn get_track_events<R: Read + Seek>(res: &mut Vec<MidiEvent>,
+                    mut file: &mut R,
+                    track_index: u16,
+                    fail_on_tempo_event: bool,
+                    options: ParseOptions,
+                    warnings: &mut Vec<MidiParseError>)
+                    -> Result<(), MidiParseError> {
     // http://www.ccarh.org/courses/253/handout/smf/
     //
     // A track chunk consists of a literal identifier string, a length indicator
@@ -347,135 +827,166 @@ fn get_track_events(res: &mut Vec<MidiEvent>,
     // <track_event>
     //     a sequenced track event.
 
-    let mut track_buffer: [u8; 4] = [0; 4];
-    if let Err(e) = file.read_exact(&mut track_buffer) {
-        return Err(format!("Failed to read the header out of file: {}", e.description()));
-    }
-
-
-    let track_header: [u8; 4] = ['M' as u8, 'T' as u8, 'r' as u8, 'k' as u8];
-    if !is_header_correct(track_buffer, track_header) {
-        return Err(format!("Invalid midi file. Couldn't read the track header"));
-    }
-
-    let track_length: u32 = match file.read_u32::<BigEndian>() {
-        Err(e) => return Err(format!("Error while reading file: {}", e.description())),
-        Ok(v) => v,
-    };
+    let track_length = read_track_header(&mut file, track_index, options, warnings)?;
 
     let track_start = match file.seek(SeekFrom::Current(0)) {
-        Err(e) => {
-            return Err(format!("Failed to get the position into the file for the beginning of a track: {}",
-                               e.description()))
-        }
+        Err(e) => return Err(error_at(file, MidiParseErrorKind::Io(e)).in_track(track_index, None)),
         Ok(v) => v,
     };
 
     let mut last_status_byte: u8 = 0x00;
-    let mut this_time_in_ns: u64 = 0; // unit is nanoseconds
+    let mut this_ticks: u64 = 0;
+    let mut event_start = track_start;
+
+    for event_index in 0.. {
+        if options.lenient && (event_start == track_start + track_length as u64) && is_at_track_boundary(&mut file)? {
+            warnings.push(MidiParseError::from(MidiParseErrorKind::MissingEndOfTrack)
+                              .at_offset(event_start)
+                              .in_track(track_index, Some(event_index)));
+            break;
+        }
 
-    loop {
-        let event = get_event(&mut file, last_status_byte)?;
+        let event = get_event(&mut file, last_status_byte)
+            .map_err(|e| e.in_track(track_index, Some(event_index)))?;
 
         // the time of an event was given related to the former event.
         // this is about making it relative to the beginning of the song
         let event = MidiEvent {
-            time: event.time + this_time_in_ns,
+            ticks: event.ticks + this_ticks,
+            time: 0,
+            track: track_index,
             data: event.data,
         };
-        this_time_in_ns = event.time;
+        this_ticks = event.ticks;
 
         last_status_byte = event.data[0];
 
-        if (event.data[0] == 0xFF) && (event.data[1] == 0x51) // This is a tempo event
+        // reject invalid META events now, while their location is known. In
+        // lenient mode, they are dropped.
+        let is_valid = if event.data[0] == 0xFF {
+            match parse_meta_event(&event.data) {
+                Ok(_) => true,
+                Err(e) => {
+                    let error = e.at_offset(event_start).in_track(track_index, Some(event_index));
+                    if !options.lenient {
+                        return Err(error);
+                    }
+                    warnings.push(error);
+                    false
+                }
+            }
+        } else {
+            true
+        };
+
+        if is_valid && (event.data[0] == 0xFF) && (event.data[1] == 0x51) // This is a tempo event
             && fail_on_tempo_event {
-            return Err("Error: a tempo event found at a forbidden place".to_owned());
+            return Err(MidiParseError::from(MidiParseErrorKind::ForbiddenTempoEvent)
+                           .at_offset(event_start)
+                           .in_track(track_index, Some(event_index)));
         }
 
         let end_of_track_found = (event.data[0] == 0xFF) && (event.data[1] == 0x2F);
 
-        res.push(event);
+        if is_valid {
+            res.push(event);
+        }
 
         if end_of_track_found {
             break;
         }
+
+        event_start = match file.seek(SeekFrom::Current(0)) {
+            Err(e) => return Err(error_at(file, MidiParseErrorKind::Io(e)).in_track(track_index, Some(event_index))),
+            Ok(v) => v,
+        };
     }
 
     let track_end = match file.seek(SeekFrom::Current(0)) {
-        Err(e) => {
-            return Err(format!("Failed to get the position into the file for the end of a track: {}",
-                               e.description()))
-        }
+        Err(e) => return Err(error_at(file, MidiParseErrorKind::Io(e)).in_track(track_index, None)),
         Ok(v) => v,
     };
 
     if track_end - track_start != track_length as u64 {
-        return Err("Error: invalid track length detected".to_owned());
+        let kind = MidiParseErrorKind::InvalidTrackLength {
+            declared: track_length,
+            actual: track_end - track_start,
+        };
+        let error = MidiParseError::from(kind).at_offset(track_end).in_track(track_index, None);
+        if !options.lenient {
+            return Err(error);
+        }
+        warnings.push(error);
     }
 
     Ok(())
 }
 
-fn set_real_timings(events: &mut [MidiEvent],
-                    tickdiv: u16,
-                    timing_style: TempoStyle)
-                    -> Result<(), String> {
-    // pre condition, events must be sorted!
-    for i in 1..events.len() {
-        if events[i].time < events[i - 1].time {
-            panic!("events must be sorted by time!".to_owned());
-        }
-    }
+pub struct MidiSong {
+    pub events: Vec<MidiEvent>,
+    pub meta_events: Vec<MidiMetaEvent>,
+    pub tempo_map: TempoMap,
+}
 
-    match timing_style {
-        TempoStyle::Timecode => {
-            for event in events {
-                event.time = event.time * tickdiv as u64 * 1_000_000;
-            }
-        }
-        TempoStyle::MetricalTiming => {
-            let mut ref_ticks: u64 = 0;
-            let mut ref_time_in_ns: u64 = 0;
-
-            // default tempo is 120 beats per minutes
-            // 1 minute -> 60 000 000 microseconds
-            // 60000000 / 120 -> 500 000 microseconds per quarter note
-            let mut us_per_quarter_note: u64 = 500_000;
-            for event in events.iter_mut() {
-                let last_ticks = event.time;
-                let delta_ticks = last_ticks - ref_ticks;
-                event.time = ref_time_in_ns +
-                             ((delta_ticks * us_per_quarter_note * 1_000) / tickdiv as u64);
-
-                if (event.data[0] == 0xFF) && (event.data[1] == 0x51) {
-                    // this is a tempo event
-                    if event.data.len() != 6 {
-                        return Err("tempo has an invalid size".to_owned());
-                    }
+// sorts the events of one song, builds its tempo map, computes the times of
+// the events in nanoseconds and separates the midi events from the meta ones
+fn extract_song(mut events: Vec<MidiEvent>, division: TimeDivision) -> MidiSong {
+    // the sort is stable: events occuring at the same time keep their order
+    events.sort_by_key(|x| x.ticks);
 
-                    ref_ticks = last_ticks;
-                    ref_time_in_ns = event.time;
+    let mut res = MidiSong {
+        events: Vec::new(),
+        meta_events: Vec::new(),
+        tempo_map: TempoMap::new(division),
+    };
 
-                    us_per_quarter_note = ((event.data[3] as u64) << 16) |
-                                          ((event.data[4] as u64) << 8) |
-                                          (event.data[5] as u64);
+    // sysex events are discarded
+    for event in events {
+        if event.data[0] == 0xFF {
+            // the invalid ones were left out by get_track_events
+            let meta_event = match parse_meta_event(&event.data) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            match meta_event {
+                MetaEvent::Tempo(us_per_quarter_note) => {
+                    res.tempo_map.add_tempo_change(event.ticks, us_per_quarter_note)
                 }
+                MetaEvent::TimeSignature { numerator, denominator, .. } => {
+                    res.tempo_map.add_time_signature(event.ticks, numerator, denominator)
+                }
+                _ => (),
             }
+            res.meta_events.push(MidiMetaEvent {
+                                     ticks: event.ticks,
+                                     time: 0,
+                                     event: meta_event,
+                                 });
+        } else if (event.data[0] & 0xF0) != 0xF0 {
+            res.events.push(event);
         }
     }
 
-    return Ok(());
-}
-
+    for event in res.events.iter_mut() {
+        event.time = res.tempo_map.get_time_in_ns(event.ticks);
+    }
+    for event in res.meta_events.iter_mut() {
+        event.time = res.tempo_map.get_time_in_ns(event.ticks);
+    }
 
-pub fn get_midi_events(filename: &str) -> Result<Vec<MidiEvent>, String> {
-    let mut file = match std::fs::File::open(filename) {
-        Err(e) => {
-            return Err(format!("Failed to open file {}: {}", filename, e.description()));
-        }
-        Ok(f) => f,
-    };
+    return res;
+}
 
+// returns the songs contained in the midi data. Single track (format 0) and
+// multiple track (format 1) files always contain exactly one song, whereas
+// multiple song (format 2) files contain one independent song per track.
+//
+// In lenient mode, slightly broken midi data is accepted: the problems found
+// are pushed into warnings instead of making the parsing fail.
+pub fn read_midi_songs<R: Read + Seek>(mut file: &mut R,
+                                       options: ParseOptions,
+                                       warnings: &mut Vec<MidiParseError>)
+                                       -> Result<Vec<MidiSong>, MidiParseError> {
     // http://www.ccarh.org/courses/253/handout/smf/
     //
     //    header_chunk = "MThd" + <header_length> + <format> + <n> + <division>
@@ -504,68 +1015,427 @@ pub fn get_midi_events(filename: &str) -> Result<Vec<MidiEvent>, String> {
     //     compatible units.
 
 
-    read_magic_number(&mut file, filename)?;
-    read_header_size(&mut file, filename)?;
-    let midi_type = read_midi_type(&mut file, filename)?;
-    if midi_type == MidiType::MultipleSong {
-        return Err("this program doesn't handle multiple song midi files - yet -".to_owned());
-    }
+    let data_end = read_magic_number(&mut file)?;
+    read_header_size(&mut file)?;
+    let midi_type = read_midi_type(&mut file)?;
 
-    let nb_tracks = read_nb_tracks(&mut file, filename)?;
+    let nb_tracks = read_nb_tracks(&mut file)?;
     if (midi_type == MidiType::SingleTrack) && (nb_tracks != 1) {
-        return Err(format!("Midi file {} is supposed to be a single track one but it says it contains {} tracks",
-                           filename,
-                           nb_tracks));
+        return Err(error_at(file, MidiParseErrorKind::InvalidTrackCount(nb_tracks)));
     }
 
-    let (tickdiv, timing_type) = get_tickdiv(&mut file, filename)?;
-    if tickdiv == 0 {
-        return Err("Error: a quarter note is made of 0 pulses (which is impossible) according to the midi data".to_owned());
-    }
+    let division = get_time_division(&mut file)?;
 
-    let mut events: Vec<MidiEvent> = Vec::new();
+    let mut tracks: Vec<Vec<MidiEvent>> = Vec::new();
 
     for i in 0..nb_tracks {
-        get_track_events(&mut events,
-                         &mut file,
-                         (midi_type == MidiType::MultipleTrack) && (i != 0))?;
+        let mut track_events: Vec<MidiEvent> = Vec::new();
+        let track_res = get_track_events(&mut track_events,
+                                         &mut file,
+                                         i,
+                                         (midi_type == MidiType::MultipleTrack) && (i != 0),
+                                         options,
+                                         warnings);
+        match track_res {
+            Ok(()) => tracks.push(track_events),
+            Err(e) => {
+                if !options.lenient || !e.is_unexpected_end_of_input() {
+                    return Err(e);
+                }
+
+                // the midi data is truncated: keep what could be read
+                warnings.push(e);
+                if !track_events.is_empty() {
+                    tracks.push(track_events);
+                }
+                break;
+            }
+        }
     }
 
-    // by now the whole file should have been read
-    let metadata = match file.metadata() {
-        Err(e) => return Err(format!("Failed to read the file size: {}", e.description())),
-        Ok(v) => v,
+    // by now the whole midi data should have been read, except for chunks of
+    // unknown types. Within a RIFF container, other chunks may follow the midi
+    // data though.
+    if skip_trailing_chunks(&mut file, data_end, options, warnings)? {
+        if !options.lenient {
+            return Err(error_at(file, MidiParseErrorKind::ExtraBytes));
+        }
+        warnings.push(error_at(file, MidiParseErrorKind::ExtraBytes));
+    }
+
+    // the tracks of a multiple song file are independent from each other (each one
+    // has its own tempo events), whereas the tracks of the other types are played
+    // all together.
+    let songs: Vec<Vec<MidiEvent>> = match midi_type {
+        MidiType::MultipleSong => tracks,
+        MidiType::SingleTrack | MidiType::MultipleTrack => {
+            vec![tracks.into_iter().flat_map(|track| track.into_iter()).collect()]
+        }
     };
 
-    let file_size = metadata.len();
+    let mut res: Vec<MidiSong> = Vec::new();
+    for events in songs {
+        res.push(extract_song(events, division));
+    }
+
+    if res.is_empty() {
+        return Err(MidiParseError::from(MidiParseErrorKind::NoSong));
+    }
+
+    return Ok(res);
+}
 
-    let nb_read_bytes = match file.seek(SeekFrom::Current(0)) {
-        Err(e) => {
-            return Err(format!("Failed to get the position into the file for the end of a track: {}",
-                               e.description()))
+// reads the songs out of the midi file. "-" means the standard input.
+// See read_midi_songs for the meaning of options and warnings.
+pub fn get_midi_songs(filename: &str,
+                      options: ParseOptions,
+                      warnings: &mut Vec<MidiParseError>)
+                      -> Result<Vec<MidiSong>, MidiParseError> {
+    if filename == "-" {
+        // stdin can't seek, hence the midi data is fully read in memory first
+        let mut buffer: Vec<u8> = Vec::new();
+        if let Err(e) = std::io::stdin().read_to_end(&mut buffer) {
+            return Err(MidiParseError::from(MidiParseErrorKind::Io(e)));
         }
-        Ok(v) => v,
+
+        return read_midi_songs(&mut std::io::Cursor::new(buffer), options, warnings);
+    }
+
+    let mut file = match std::fs::File::open(filename) {
+        Err(e) => return Err(MidiParseError::from(MidiParseErrorKind::Io(e))),
+        Ok(f) => f,
     };
 
-    if nb_read_bytes != file_size {
-        return Err("file contains extra bytes after end of MIDI data".to_owned());
+    read_midi_songs(&mut file, options, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const STRICT: ParseOptions = ParseOptions { lenient: false, report_unknown_chunks: false };
+    const LENIENT: ParseOptions = ParseOptions { lenient: true, report_unknown_chunks: false };
+    const VERBOSE: ParseOptions = ParseOptions { lenient: false, report_unknown_chunks: true };
+
+    // a note on at 0 and a note off at 96 ticks, then the end of the track
+    const NOTE_TRACK: [u8; 12] = [0x00, 0x90, 60, 100, 0x60, 0x80, 60, 0, 0x00, 0xFF, 0x2F, 0x00];
+
+    fn chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8> {
+        let length = data.len() as u32;
+        let mut res = chunk_type.to_vec();
+        res.extend_from_slice(&[(length >> 24) as u8, (length >> 16) as u8, (length >> 8) as u8, length as u8]);
+        res.extend_from_slice(data);
+        res
+    }
+
+    fn header(format: u8, nb_tracks: u8, division: [u8; 2]) -> Vec<u8> {
+        chunk(b"MThd", &[0, format, 0, nb_tracks, division[0], division[1]])
     }
 
-    events.sort_by(|a, b| match (a, b) {
-                       (a, b) if a.time < b.time => std::cmp::Ordering::Less,
-                       (a, b) if a.time > b.time => std::cmp::Ordering::Greater,
-                       _ => std::cmp::Ordering::Equal,
-                   });
+    // format 0 midi data holding NOTE_TRACK, at 96 ticks per quarter note
+    fn note_file() -> Vec<u8> {
+        let mut res = header(0, 1, [0, 96]);
+        res.extend(chunk(b"MTrk", &NOTE_TRACK));
+        res
+    }
 
-    set_real_timings(&mut events, tickdiv, timing_type)?;
+    fn read(data: Vec<u8>, options: ParseOptions) -> (Result<Vec<MidiSong>, MidiParseError>, Vec<MidiParseError>) {
+        let mut warnings = Vec::new();
+        let res = read_midi_songs(&mut Cursor::new(data), options, &mut warnings);
+        (res, warnings)
+    }
 
-    // keep only the midi events
-    let mut res: Vec<MidiEvent> = Vec::new();
+    fn get_events(song: &MidiSong) -> Vec<(u64, u64, u16, Vec<u8>)> {
+        song.events.iter().map(|x| (x.ticks, x.time, x.track, x.data.clone())).collect()
+    }
 
-    for event in events {
-        if (event.data[0] & 0xF0) != 0xF0 {
-            res.push(event);
+    fn note_events(track: u16) -> Vec<(u64, u64, u16, Vec<u8>)> {
+        vec![(0, 0, track, vec![0x90, 60, 100]), (96, 500_000_000, track, vec![0x80, 60, 0])]
+    }
+
+    #[test]
+    fn reads_a_single_track_file() {
+        let (res, warnings) = read(note_file(), STRICT);
+        let songs = res.unwrap();
+        assert_eq!(songs.len(), 1);
+        assert_eq!(get_events(&songs[0]), note_events(0));
+        assert_eq!(songs[0].meta_events.len(), 1);
+        assert!(warnings.is_empty());
+    }
+
+    // the time of the note off of a note held 1000 ticks, with the given
+    // SMPTE frames per second and ticks per frame
+    fn get_timecode_note_end(frames_per_second: u8, ticks_per_frame: u8) -> Result<u64, MidiParseError> {
+        let mut data = header(0, 1, [(-(frames_per_second as i8)) as u8, ticks_per_frame]);
+        data.extend(chunk(b"MTrk", &[0x00, 0x90, 60, 100, 0x87, 0x68, 0x80, 60, 0, 0x00, 0xFF, 0x2F, 0x00]));
+        read(data, STRICT).0.map(|songs| songs[0].events[1].time)
+    }
+
+    #[test]
+    fn converts_timecode_ticks_to_time() {
+        // 24 * 4, 25 * 40 and 30 * 80 ticks per second
+        assert_eq!(get_timecode_note_end(24, 4).unwrap(), 10_416_666_666);
+        assert_eq!(get_timecode_note_end(25, 40).unwrap(), 1_000_000_000);
+        assert_eq!(get_timecode_note_end(30, 80).unwrap(), 416_666_666);
+
+        // 29 stands for 30 drop frame: the ticks are 1001/1000 times longer
+        assert_eq!(get_timecode_note_end(29, 4).unwrap(), 8_341_666_666);
+        assert_eq!(get_timecode_note_end(29, 40).unwrap(), 834_166_666);
+    }
+
+    #[test]
+    fn rejects_invalid_timecode_divisions() {
+        for &frames_per_second in [24, 25, 29, 30].iter() {
+            let error = get_timecode_note_end(frames_per_second, 0).err().unwrap();
+            match error.kind {
+                MidiParseErrorKind::NullTimeDivision => (),
+                ref kind => panic!("unexpected error: {}", kind),
+            }
+            assert_eq!(error.offset, Some(14));
+        }
+
+        match get_timecode_note_end(20, 40).err().unwrap().kind {
+            MidiParseErrorKind::InvalidFramesPerSecond(20) => (),
+            ref kind => panic!("unexpected error: {}", kind),
         }
     }
-    return Ok(res);
+
+    #[test]
+    fn reads_each_track_of_a_multiple_song_file_as_a_song() {
+        let mut data = header(2, 2, [0, 96]);
+        data.extend(chunk(b"MTrk", &NOTE_TRACK));
+        data.extend(chunk(b"MTrk", &NOTE_TRACK));
+
+        let songs = read(data, STRICT).0.unwrap();
+        assert_eq!(songs.len(), 2);
+        assert_eq!(get_events(&songs[0]), note_events(0));
+        assert_eq!(get_events(&songs[1]), note_events(1));
+    }
+
+    #[test]
+    fn rejects_an_invalid_magic_number() {
+        let mut data = note_file();
+        data[0] = b'X';
+
+        let error = read(data, STRICT).0.err().unwrap();
+        match error.kind {
+            MidiParseErrorKind::InvalidMagicNumber => (),
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+        assert_eq!(error.offset, Some(4));
+    }
+
+    #[test]
+    fn locates_a_wrong_track_length() {
+        // the track holds 12 bytes, the track header starts at 14
+        let mut data = note_file();
+        data[21] = 14;
+
+        let error = read(data, STRICT).0.err().unwrap();
+        match error.kind {
+            MidiParseErrorKind::InvalidTrackLength { declared: 14, actual: 12 } => (),
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+        assert_eq!(error.offset, Some(34));
+        assert_eq!(error.track, Some(0));
+        assert_eq!(error.event, None);
+    }
+
+    #[test]
+    fn locates_an_unknown_event() {
+        let mut data = header(0, 1, [0, 96]);
+        data.extend(chunk(b"MTrk", &[0x00, 0x90, 60, 100, 0x00, 0xF4, 0x00, 0xFF, 0x2F, 0x00]));
+
+        let error = read(data, STRICT).0.err().unwrap();
+        match error.kind {
+            MidiParseErrorKind::UnknownEvent(0xF4) => (),
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+        assert_eq!(error.offset, Some(28));
+        assert_eq!(error.track, Some(0));
+        assert_eq!(error.event, Some(1));
+    }
+
+    #[test]
+    fn reports_a_wrong_track_length_in_lenient_mode() {
+        let mut data = note_file();
+        data[21] = 10;
+
+        let (res, warnings) = read(data, LENIENT);
+        assert_eq!(get_events(&res.unwrap()[0]), note_events(0));
+        assert_eq!(warnings.len(), 1);
+        match warnings[0].kind {
+            MidiParseErrorKind::InvalidTrackLength { declared: 10, actual: 12 } => (),
+            ref kind => panic!("unexpected warning: {}", kind),
+        }
+        assert_eq!(warnings[0].track, Some(0));
+    }
+
+    #[test]
+    fn keeps_the_events_of_a_truncated_track_in_lenient_mode() {
+        // cut in the middle of the note off event
+        let mut data = note_file();
+        data.truncate(22 + 6);
+
+        let strict_error = read(data.clone(), STRICT).0.err().unwrap();
+        assert!(strict_error.is_unexpected_end_of_input());
+        assert_eq!(strict_error.track, Some(0));
+        assert_eq!(strict_error.event, Some(1));
+
+        let (res, warnings) = read(data, LENIENT);
+        assert_eq!(get_events(&res.unwrap()[0]), vec![(0, 0, 0, vec![0x90, 60, 100])]);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].is_unexpected_end_of_input());
+        assert_eq!(warnings[0].offset, Some(28));
+        assert_eq!(warnings[0].track, Some(0));
+    }
+
+    #[test]
+    fn ends_a_track_missing_its_end_in_lenient_mode() {
+        let mut data = header(1, 2, [0, 96]);
+        data.extend(chunk(b"MTrk", &NOTE_TRACK[..8]));
+        data.extend(chunk(b"MTrk", &NOTE_TRACK));
+
+        assert!(read(data.clone(), STRICT).0.is_err());
+
+        let (res, warnings) = read(data, LENIENT);
+        let mut expected = note_events(0);
+        expected.extend(note_events(1));
+        let mut events = get_events(&res.unwrap()[0]);
+        events.sort_by_key(|x| (x.2, x.0));
+        assert_eq!(events, expected);
+        assert_eq!(warnings.len(), 1);
+        match warnings[0].kind {
+            MidiParseErrorKind::MissingEndOfTrack => (),
+            ref kind => panic!("unexpected warning: {}", kind),
+        }
+        assert_eq!(warnings[0].offset, Some(30));
+        assert_eq!(warnings[0].track, Some(0));
+        assert_eq!(warnings[0].event, Some(2));
+    }
+
+    #[test]
+    fn skips_unknown_chunks() {
+        let mut data = header(0, 1, [0, 96]);
+        data.extend(chunk(b"XFIH", &[1, 2, 3]));
+        data.extend(chunk(b"MTrk", &NOTE_TRACK));
+        data.extend(chunk(b"XFKM", &[4, 5]));
+
+        let (res, warnings) = read(data.clone(), STRICT);
+        assert_eq!(get_events(&res.unwrap()[0]), note_events(0));
+        assert!(warnings.is_empty());
+
+        let (res, warnings) = read(data, VERBOSE);
+        assert!(res.is_ok());
+        let chunk_types: Vec<[u8; 4]> = warnings.iter()
+            .map(|x| match x.kind {
+                     MidiParseErrorKind::UnknownChunk(chunk_type) => chunk_type,
+                     ref kind => panic!("unexpected warning: {}", kind),
+                 })
+            .collect();
+        assert_eq!(chunk_types, vec![*b"XFIH", *b"XFKM"]);
+        assert_eq!(warnings[0].track, Some(0));
+        assert_eq!(warnings[1].track, None);
+    }
+
+    #[test]
+    fn rejects_extra_bytes_after_the_last_track() {
+        let mut data = note_file();
+        data.extend(chunk(b"XFIH", &[1, 2, 3]));
+        data.extend_from_slice(&[0, 1, 2]);
+
+        let error = read(data.clone(), STRICT).0.err().unwrap();
+        match error.kind {
+            MidiParseErrorKind::ExtraBytes => (),
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+        assert_eq!(error.offset, Some(34 + 11));
+
+        let (res, warnings) = read(data, LENIENT);
+        assert!(res.is_ok());
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn unwraps_a_riff_rmid_container() {
+        let smf = note_file();
+        let mut riff_data = b"RMID".to_vec();
+        riff_data.extend_from_slice(&[b'd', b'a', b't', b'a', smf.len() as u8, 0, 0, 0]);
+        riff_data.extend(smf);
+        riff_data.extend_from_slice(&[b'I', b'N', b'F', b'O', 3, 0, 0, 0, 1, 2, 3, 0]);
+
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&[riff_data.len() as u8, 0, 0, 0]);
+        data.extend(riff_data);
+
+        let (res, warnings) = read(data, STRICT);
+        assert_eq!(get_events(&res.unwrap()[0]), note_events(0));
+        assert!(warnings.is_empty());
+    }
+
+    // format 0 midi data holding the meta event at 0 ticks, followed by NOTE_TRACK
+    fn meta_event_file(meta_event: &[u8]) -> Vec<u8> {
+        let mut track = vec![0x00];
+        track.extend_from_slice(meta_event);
+        track.extend_from_slice(&NOTE_TRACK);
+        let mut res = header(0, 1, [0, 96]);
+        res.extend(chunk(b"MTrk", &track));
+        res
+    }
+
+    #[test]
+    fn keeps_a_sequence_number_without_data() {
+        let (res, warnings) = read(meta_event_file(&[0xFF, 0x00, 0x00]), STRICT);
+        let songs = res.unwrap();
+        assert_eq!(get_events(&songs[0]), note_events(0));
+        match songs[0].meta_events[0].event {
+            MetaEvent::Unknown(0x00, ref data) if data.is_empty() => (),
+            ref event => panic!("unexpected meta event: {}", event),
+        }
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn keeps_meta_events_of_unexpected_sizes_as_unknown() {
+        // a key signature one byte too long, and a time signature in 65536th
+        // notes
+        let events: [&[u8]; 2] = [&[0xFF, 0x59, 0x03, 0x02, 0x00, 0x00], &[0xFF, 0x58, 0x04, 0x06, 0x10, 0x18, 0x08]];
+        for event in events.iter() {
+            let songs = read(meta_event_file(event), STRICT).0.unwrap();
+            assert_eq!(get_events(&songs[0]), note_events(0));
+            match songs[0].meta_events[0].event {
+                MetaEvent::Unknown(meta_type, ref data) => assert_eq!((meta_type, &data[..]), (event[1], &event[3..])),
+                ref event => panic!("unexpected meta event: {}", event),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_an_invalid_tempo() {
+        let error = read(meta_event_file(&[0xFF, 0x51, 0x02, 0x07, 0xA1]), STRICT).0.err().unwrap();
+        match error.kind {
+            MidiParseErrorKind::InvalidMetaEvent { meta_type: 0x51, size: 2 } => (),
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+        assert_eq!(error.offset, Some(22));
+        assert_eq!(error.track, Some(0));
+        assert_eq!(error.event, Some(0));
+    }
+
+    #[test]
+    fn drops_an_invalid_tempo_in_lenient_mode() {
+        let (res, warnings) = read(meta_event_file(&[0xFF, 0x51, 0x02, 0x07, 0xA1]), LENIENT);
+        let songs = res.unwrap();
+        assert_eq!(get_events(&songs[0]), note_events(0));
+        assert_eq!(songs[0].meta_events.len(), 1);
+        assert_eq!(warnings.len(), 1);
+        match warnings[0].kind {
+            MidiParseErrorKind::InvalidMetaEvent { meta_type: 0x51, size: 2 } => (),
+            ref kind => panic!("unexpected warning: {}", kind),
+        }
+        assert_eq!(warnings[0].offset, Some(22));
+        assert_eq!(warnings[0].track, Some(0));
+        assert_eq!(warnings[0].event, Some(0));
+    }
 }
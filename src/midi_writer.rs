@@ -0,0 +1,367 @@
+extern crate byteorder;
+use midi_writer::byteorder::{WriteBytesExt, BigEndian};
+
+use std::io::prelude::*;
+use std::error::Error;
+use std;
+use midi_reader::{MidiEvent, MidiMetaEvent, MetaEvent};
+use tempo_map::{TempoMap, TimeDivision};
+
+// 960 ticks per quarter note keeps the rounding errors under the millisecond
+// for any usual tempo, e.g. when recording
+pub const DEFAULT_TICKDIV: u16 = 960;
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum SmfFormat {
+    SingleTrack = 0,   // every event in one track
+    MultipleTrack = 1, // meta events in the first track, then one track per channel
+}
+
+fn write_variable_length_value(res: &mut Vec<u8>, value: u64) -> Result<(), String> {
+    // a variable length value is at most four bytes long, hence 28 bits
+    if value > 0x0FFF_FFFF {
+        return Err(format!("value {} is too big to be written as a variable length value", value));
+    }
+
+    // the most significant groups of 7 bits come first. All bytes but the last
+    // one have their continuation bit on
+    let mut groups: Vec<u8> = vec![(value & 0x7F) as u8];
+    let mut remaining = value >> 7;
+    while remaining != 0 {
+        groups.push(((remaining & 0x7F) as u8) | 0x80);
+        remaining >>= 7;
+    }
+
+    for byte in groups.iter().rev() {
+        res.push(*byte);
+    }
+
+    Ok(())
+}
+
+// returns the meta type and the data of the meta event
+fn get_meta_event_data(event: &MetaEvent) -> (u8, Vec<u8>) {
+    let text = |text: &String| text.as_bytes().to_vec();
+
+    match *event {
+        MetaEvent::SequenceNumber(n) => (0x00, vec![(n >> 8) as u8, (n & 0xFF) as u8]),
+        MetaEvent::Text(ref x) => (0x01, text(x)),
+        MetaEvent::Copyright(ref x) => (0x02, text(x)),
+        MetaEvent::TrackName(ref x) => (0x03, text(x)),
+        MetaEvent::InstrumentName(ref x) => (0x04, text(x)),
+        MetaEvent::Lyric(ref x) => (0x05, text(x)),
+        MetaEvent::Marker(ref x) => (0x06, text(x)),
+        MetaEvent::CuePoint(ref x) => (0x07, text(x)),
+        MetaEvent::ChannelPrefix(channel) => (0x20, vec![channel]),
+        MetaEvent::EndOfTrack => (0x2F, vec![]),
+        MetaEvent::Tempo(us_per_quarter_note) => {
+            (0x51, vec![(us_per_quarter_note >> 16) as u8, (us_per_quarter_note >> 8) as u8, us_per_quarter_note as u8])
+        }
+        MetaEvent::SmpteOffset { hours, minutes, seconds, frames, subframes } => {
+            (0x54, vec![hours, minutes, seconds, frames, subframes])
+        }
+        MetaEvent::TimeSignature { numerator, denominator, clocks_per_click, notated_32nd_per_quarter } => {
+            // the denominator is written as a power of two
+            let mut power: u8 = 0;
+            while (1u32 << (power + 1)) <= denominator as u32 {
+                power += 1;
+            }
+            (0x58, vec![numerator, power, clocks_per_click, notated_32nd_per_quarter])
+        }
+        MetaEvent::KeySignature { sharps, minor } => (0x59, vec![sharps as u8, minor as u8]),
+        MetaEvent::SequencerSpecific(ref data) => (0x7F, data.clone()),
+        MetaEvent::Unknown(meta_type, ref data) => (meta_type, data.clone()),
+    }
+}
+
+// an event to write, with its time in ticks
+enum TrackEvent<'a> {
+    Midi(u64, &'a MidiEvent),
+    Meta(u64, &'a MetaEvent),
+}
+
+// serialises a track chunk. events must be sorted by time
+fn write_track<W: Write>(output: &mut W, events: &[TrackEvent], end_of_track_ticks: u64) -> Result<(), String> {
+    let mut data: Vec<u8> = Vec::new();
+    let mut last_ticks: u64 = 0;
+    let mut last_status_byte: Option<u8> = None;
+
+    for event in events {
+        match *event {
+            TrackEvent::Midi(ticks, midi_event) => {
+                write_variable_length_value(&mut data, ticks - last_ticks)?;
+                last_ticks = ticks;
+
+                // running status: the status byte is omitted when it is the
+                // same as the one of the previous channel event
+                if last_status_byte != Some(midi_event.data[0]) {
+                    data.push(midi_event.data[0]);
+                }
+                last_status_byte = Some(midi_event.data[0]);
+                data.extend_from_slice(&midi_event.data[1..]);
+            }
+            TrackEvent::Meta(ticks, meta_event) => {
+                write_variable_length_value(&mut data, ticks - last_ticks)?;
+                last_ticks = ticks;
+
+                // meta events cancel the running status
+                last_status_byte = None;
+                let (meta_type, meta_data) = get_meta_event_data(meta_event);
+                data.push(0xFF);
+                data.push(meta_type);
+                write_variable_length_value(&mut data, meta_data.len() as u64)?;
+                data.extend_from_slice(&meta_data);
+            }
+        }
+    }
+
+    write_variable_length_value(&mut data, end_of_track_ticks - last_ticks)?;
+    data.extend_from_slice(&[0xFF, 0x2F, 0x00]);
+
+    let write_res = output.write_all(&['M' as u8, 'T' as u8, 'r' as u8, 'k' as u8])
+        .and_then(|_| output.write_u32::<BigEndian>(data.len() as u32))
+        .and_then(|_| output.write_all(&data));
+
+    if let Err(e) = write_res {
+        return Err(format!("Failed to write a track: {}", e.description()));
+    }
+
+    Ok(())
+}
+
+// returns the two bytes of the division field of the header chunk
+fn get_division_bytes(division: TimeDivision) -> Result<[u8; 2], String> {
+    match division {
+        TimeDivision::TicksPerQuarterNote(tickdiv) => {
+            if (tickdiv == 0) || (tickdiv > 0x7FFF) {
+                return Err(format!("Invalid number of ticks per quarter note: {}", tickdiv));
+            }
+            Ok([(tickdiv >> 8) as u8, (tickdiv & 0xFF) as u8])
+        }
+        TimeDivision::Timecode { ticks_per_second, ticks_per_frame, drop_frame } => {
+            if ticks_per_frame == 0 {
+                return Err("Invalid number of ticks per frame: 0".to_owned());
+            }
+
+            // the number of frames per second is written negated. 29 stands
+            // for the 30 drop frame rate
+            let frames_per_second = if drop_frame { 29 } else { ticks_per_second / ticks_per_frame as u16 };
+            Ok([(-(frames_per_second as i8)) as u8, ticks_per_frame])
+        }
+    }
+}
+
+// serialises the song as a standard midi file of the given format, with the
+// time division of tempo_map. The events are written at their ticks, and
+// must be sorted by them (as returned by midi_reader).
+pub fn write_midi_song<W: Write>(output: &mut W,
+                                 midi_events: &[MidiEvent],
+                                 meta_events: &[MidiMetaEvent],
+                                 tempo_map: &TempoMap,
+                                 format: SmfFormat)
+                                 -> Result<(), String> {
+    let division_bytes = get_division_bytes(tempo_map.get_division())?;
+
+    // the end of track events are written by write_track. The end of the song
+    // is kept where the latest one was
+    let mut end_of_song_ticks: u64 = 0;
+    let mut meta_track: Vec<TrackEvent> = Vec::new();
+    for meta in meta_events {
+        let ticks = meta.ticks;
+        end_of_song_ticks = std::cmp::max(end_of_song_ticks, ticks);
+        match meta.event {
+            MetaEvent::EndOfTrack => (),
+            ref event => meta_track.push(TrackEvent::Meta(ticks, event)),
+        }
+    }
+
+    // sysex events can't be part of the midi events
+    let mut channel_tracks: Vec<Vec<TrackEvent>> = (0..16).map(|_| Vec::new()).collect();
+    for event in midi_events {
+        let status = event.data[0];
+        if (status < 0x80) || (status >= 0xF0) {
+            return Err(format!("Can't write a midi event whose status byte is 0x{:02X}", status));
+        }
+
+        let ticks = event.ticks;
+        end_of_song_ticks = std::cmp::max(end_of_song_ticks, ticks);
+        channel_tracks[(status & 0x0F) as usize].push(TrackEvent::Midi(ticks, event));
+    }
+
+    let tracks: Vec<Vec<TrackEvent>> = match format {
+        SmfFormat::SingleTrack => {
+            // merge everything, keeping the meta events first at equal times
+            let mut track: Vec<TrackEvent> = meta_track;
+            for channel_track in channel_tracks {
+                track.extend(channel_track);
+            }
+            track.sort_by_key(|x| match *x {
+                                  TrackEvent::Midi(ticks, _) | TrackEvent::Meta(ticks, _) => ticks,
+                              });
+            vec![track]
+        }
+        SmfFormat::MultipleTrack => {
+            let mut res = vec![meta_track];
+            res.extend(channel_tracks.into_iter().filter(|x| !x.is_empty()));
+            res
+        }
+    };
+
+    // header chunk: "MThd" + <header_length> + <format> + <n> + <division>
+    let header_res = output.write_all(&['M' as u8, 'T' as u8, 'h' as u8, 'd' as u8])
+        .and_then(|_| output.write_u32::<BigEndian>(6))
+        .and_then(|_| output.write_u16::<BigEndian>(format as u16))
+        .and_then(|_| output.write_u16::<BigEndian>(tracks.len() as u16))
+        .and_then(|_| output.write_all(&division_bytes));
+
+    if let Err(e) = header_res {
+        return Err(format!("Failed to write the midi header: {}", e.description()));
+    }
+
+    for track in tracks.iter() {
+        write_track(output, track, end_of_song_ticks)?;
+    }
+
+    Ok(())
+}
+
+pub fn save_midi_song(filename: &str,
+                      midi_events: &[MidiEvent],
+                      meta_events: &[MidiMetaEvent],
+                      tempo_map: &TempoMap,
+                      format: SmfFormat)
+                      -> Result<(), String> {
+    let file = match std::fs::File::create(filename) {
+        Err(e) => return Err(format!("Failed to create file {}: {}", filename, e.description())),
+        Ok(f) => f,
+    };
+
+    let mut output = std::io::BufWriter::new(file);
+    write_midi_song(&mut output, midi_events, meta_events, tempo_map, format)?;
+
+    if let Err(e) = output.flush() {
+        return Err(format!("Failed to write file {}: {}", filename, e.description()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use midi_reader::{read_midi_songs, ParseOptions};
+
+    // two chords on channel 0 and a note on channel 1, around a tempo change
+    fn get_song(tempo_map: &TempoMap) -> (Vec<MidiEvent>, Vec<MidiMetaEvent>) {
+        let midi_events: Vec<MidiEvent> = vec![(0, vec![0xC1, 5]),
+                                               (0, vec![0x90, 60, 100]),
+                                               (0, vec![0x90, 64, 90]),
+                                               (384, vec![0x80, 60, 0]),
+                                               (384, vec![0x80, 64, 0]),
+                                               (1000, vec![0x91, 67, 80]),
+                                               (1200, vec![0x91, 67, 0])]
+            .into_iter()
+            .map(|(ticks, data)| {
+                MidiEvent {
+                    ticks: ticks,
+                    time: tempo_map.get_time_in_ns(ticks),
+                    track: 0,
+                    data: data,
+                }
+            })
+            .collect();
+
+        let meta_events: Vec<MidiMetaEvent> = vec![(0, MetaEvent::TrackName("piano".to_owned())),
+                                                   (0, MetaEvent::Tempo(500_000)),
+                                                   (0, MetaEvent::TimeSignature {
+                                                       numerator: 3,
+                                                       denominator: 4,
+                                                       clocks_per_click: 24,
+                                                       notated_32nd_per_quarter: 8,
+                                                   }),
+                                                   (768, MetaEvent::Tempo(250_000))]
+            .into_iter()
+            .map(|(ticks, event)| {
+                MidiMetaEvent {
+                    ticks: ticks,
+                    time: tempo_map.get_time_in_ns(ticks),
+                    event: event,
+                }
+            })
+            .collect();
+
+        (midi_events, meta_events)
+    }
+
+    fn get_tempo_map(division: TimeDivision) -> TempoMap {
+        let mut tempo_map = TempoMap::new(division);
+        tempo_map.add_tempo_change(0, 500_000);
+        tempo_map.add_tempo_change(768, 250_000);
+        tempo_map
+    }
+
+    // the events sorted by ticks then data, without the track they come from
+    fn get_midi_data(events: &[MidiEvent]) -> Vec<(u64, u64, Vec<u8>)> {
+        let mut res: Vec<(u64, u64, Vec<u8>)> = events.iter().map(|x| (x.ticks, x.time, x.data.clone())).collect();
+        res.sort();
+        res
+    }
+
+    // the end of track events are left out, write_midi_song adds its own
+    fn get_meta_data(events: &[MidiMetaEvent]) -> Vec<(u64, u64, (u8, Vec<u8>))> {
+        let mut res: Vec<(u64, u64, (u8, Vec<u8>))> = events.iter()
+            .map(|x| (x.ticks, x.time, get_meta_event_data(&x.event)))
+            .filter(|x| (x.2).0 != 0x2F)
+            .collect();
+        res.sort();
+        res
+    }
+
+    fn contains(data: &[u8], sequence: &[u8]) -> bool {
+        data.windows(sequence.len()).any(|x| x == sequence)
+    }
+
+    fn round_trip(tempo_map: &TempoMap, format: SmfFormat) -> Vec<u8> {
+        let (midi_events, meta_events) = get_song(tempo_map);
+        let mut output: Vec<u8> = Vec::new();
+        write_midi_song(&mut output, &midi_events, &meta_events, tempo_map, format).unwrap();
+
+        let options = ParseOptions { lenient: false, report_unknown_chunks: false };
+        let mut warnings = Vec::new();
+        let songs = read_midi_songs(&mut Cursor::new(output.clone()), options, &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(songs.len(), 1);
+        assert_eq!(get_midi_data(&songs[0].events), get_midi_data(&midi_events));
+        assert_eq!(get_meta_data(&songs[0].meta_events), get_meta_data(&meta_events));
+
+        // the second event of each chord is written with the running status
+        assert!(contains(&output, &[0x90, 60, 100, 0x00, 64, 90]));
+        assert!(contains(&output, &[0x80, 60, 0, 0x00, 64, 0]));
+        output
+    }
+
+    #[test]
+    fn reads_back_a_single_track_file() {
+        let output = round_trip(&get_tempo_map(TimeDivision::TicksPerQuarterNote(384)), SmfFormat::SingleTrack);
+        assert_eq!(&output[8..14], &[0, 0, 0, 1, 0x01, 0x80]);
+    }
+
+    #[test]
+    fn reads_back_a_multiple_track_file() {
+        // the meta events, then channels 0 and 1
+        let output = round_trip(&get_tempo_map(TimeDivision::TicksPerQuarterNote(384)), SmfFormat::MultipleTrack);
+        assert_eq!(&output[8..14], &[0, 1, 0, 3, 0x01, 0x80]);
+    }
+
+    #[test]
+    fn keeps_a_timecode_division() {
+        let division = TimeDivision::Timecode { ticks_per_second: 1000, ticks_per_frame: 40, drop_frame: false };
+        let output = round_trip(&get_tempo_map(division), SmfFormat::MultipleTrack);
+        assert_eq!(&output[12..14], &[(-25i8) as u8, 40]);
+
+        let division = TimeDivision::Timecode { ticks_per_second: 120, ticks_per_frame: 4, drop_frame: true };
+        let output = round_trip(&get_tempo_map(division), SmfFormat::SingleTrack);
+        assert_eq!(&output[12..14], &[(-29i8) as u8, 4]);
+    }
+}
@@ -5,27 +5,41 @@ use std::error::Error;
 use std;
 use utils;
 use self::rustbox::{RustBox, Event, Key};
-use keyboard_events_extractor::KeyData;
+use canvas::{Canvas, Viewport};
+use keyboard_events_extractor;
+use keyboard_events_extractor::{KeyData, KeyEvent, Note};
+use midi_reader::{MetaEvent, MidiEvent};
+use midi_writer;
+use midi_writer::SmfFormat;
+use tempo_map::{TempoMap, TimeDivision};
+use scheduler::{Scheduler, SystemClock};
 use std::sync::atomic::Ordering;
 use signal_handler::{EXIT_REQUESTED_BY_SIGNAL, PAUSE_REQUESTED_BY_SIGNAL, CONTINUE_REQUESTED_BY_SIGNAL};
 
-fn draw_piano_key(ui: &RustBox, x: usize, y: usize, width: usize, height: usize, color: u16)
+// pressed keys are drawn with a denser glyph the louder they are played
+fn get_key_glyph(velocity: u8) -> u32 {
+    match velocity {
+        0 => 0x2588, // not pressed: full block
+        1..=31 => 0x2591, // light shade
+        32..=63 => 0x2592, // medium shade
+        64..=95 => 0x2593, // dark shade
+        _ => 0x2588,
+    }
+}
+
+fn draw_piano_key(canvas: &mut Canvas, x: usize, y: usize, width: usize, height: usize, color: u16, glyph: u32)
 {
     for i in x .. x + width {
         for j in y .. y + height {
-            unsafe {
-                ui.change_cell(i, j, 0x2588, color, rustbox::Color::Default.as_16color());
-            }
+            canvas.change_cell(i, j, glyph, color, rustbox::Color::Default.as_16color());
          }
     }
 }
 
-fn draw_separating_line(ui: &RustBox, x: usize, y: usize, height: usize, bg_color: u16)
+fn draw_separating_line(canvas: &mut Canvas, x: usize, y: usize, height: usize, bg_color: u16)
 {
     for j in y .. y + height {
-        unsafe {
-            ui.change_cell(x, j, 0x2502, rustbox::Color::Black.as_16color(), bg_color);
-        }
+        canvas.change_cell(x, j, 0x2502, rustbox::Color::Black.as_16color(), bg_color);
     }
 }
 
@@ -68,6 +82,21 @@ impl OctaveColor
     }
 }
 
+// colors of the normal and diese keys pressed on each midi channel. Channels
+// above the number of colors reuse them.
+const CHANNEL_COLORS: [(rustbox::Color, rustbox::Color); 5] = [(rustbox::Color::Blue, rustbox::Color::Cyan),
+                                                              (rustbox::Color::Green, rustbox::Color::Green),
+                                                              (rustbox::Color::Red, rustbox::Color::Red),
+                                                              (rustbox::Color::Magenta, rustbox::Color::Magenta),
+                                                              (rustbox::Color::Cyan, rustbox::Color::Blue)];
+
+// color of the keys only held by a pedal, when they are shown apart
+const PEDAL_HELD_KEY_COLOR: rustbox::Color = rustbox::Color::Yellow;
+
+const SUSTAIN_PEDAL: u8 = 64; // also known as damper pedal
+const SOSTENUTO_PEDAL: u8 = 66;
+const SOFT_PEDAL: u8 = 67;
+
 struct KeysColor
 {
     la_0_color: u8,
@@ -75,11 +104,21 @@ struct KeysColor
     si_0_color: u8,
     octaves: [OctaveColor; 7],
     do_8_color: u8,
+    pressed_channels: [u16; 128], // bit i is set when the key is held on channel i
+    velocities: [u8; 128], // velocity of the last press of each held key, 0 for released keys
+
+    // pedals: bit i is set when the pedal is down on channel i
+    sustain_channels: u16,
+    sostenuto_channels: u16,
+    soft_channels: u16,
+    sostenuto_keys: [u16; 128], // keys pressed when the sostenuto pedal went down
+    pedal_held_channels: [u16; 128], // keys released while a pedal was holding them
+    show_pedal_held_keys: bool, // draw the keys only held by a pedal in their own color
 }
 
 impl KeysColor
 {
-    pub fn new() -> Self
+    pub fn new(show_pedal_held_keys: bool) -> Self
     {
         KeysColor {
             la_0_color:  rustbox::Color::White.as_16color() as u8,
@@ -87,6 +126,14 @@ impl KeysColor
             si_0_color:  rustbox::Color::White.as_16color() as u8,
             octaves: [OctaveColor::new(); 7],
             do_8_color:  rustbox::Color::White.as_16color() as u8,
+            pressed_channels: [0; 128],
+            velocities: [0; 128],
+            sustain_channels: 0,
+            sostenuto_channels: 0,
+            soft_channels: 0,
+            sostenuto_keys: [0; 128],
+            pedal_held_channels: [0; 128],
+            show_pedal_held_keys: show_pedal_held_keys,
         }
     }
 
@@ -120,212 +167,1057 @@ impl KeysColor
         }
     }
 
-    pub fn reset_color(&mut self, pitch: u8) {
-        self.set_color_(pitch, rustbox::Color::White, rustbox::Color::Black);
+    // a key held on several channels gets the color of the lowest one. Keys
+    // held by a pedal only are drawn as if they were still pressed, unless they
+    // are shown apart.
+    fn update_color(&mut self, pitch: u8) {
+        let pressed_channels = self.pressed_channels[pitch as usize];
+        let pedal_held_channels = self.pedal_held_channels[pitch as usize];
+        match (pressed_channels, pedal_held_channels) {
+            (0, 0) => {
+                self.velocities[pitch as usize] = 0;
+                self.set_color_(pitch, rustbox::Color::White, rustbox::Color::Black);
+            }
+            (0, _) if self.show_pedal_held_keys => {
+                self.set_color_(pitch, PEDAL_HELD_KEY_COLOR, PEDAL_HELD_KEY_COLOR);
+            }
+            (0, channels) | (channels, _) => {
+                let (normal_key_color, diese_key_color) = CHANNEL_COLORS[channels.trailing_zeros() as usize % CHANNEL_COLORS.len()];
+                self.set_color_(pitch, normal_key_color, diese_key_color);
+            }
+        }
     }
 
-    pub fn set_color(&mut self, pitch: u8) {
-        self.set_color_(pitch, rustbox::Color::Blue, rustbox::Color::Cyan);
+    pub fn press_key(&mut self, pitch: u8, channel: u8, velocity: u8) {
+        self.pressed_channels[pitch as usize] |= 1 << channel;
+        self.pedal_held_channels[pitch as usize] &= !(1 << channel);
+        self.velocities[pitch as usize] = velocity;
+        self.update_color(pitch);
     }
 
+    pub fn release_key(&mut self, pitch: u8, channel: u8) {
+        let channel_bit: u16 = 1 << channel;
+        self.pressed_channels[pitch as usize] &= !channel_bit;
+        if ((self.sustain_channels & channel_bit) != 0) || ((self.sostenuto_keys[pitch as usize] & channel_bit) != 0) {
+            self.pedal_held_channels[pitch as usize] |= channel_bit;
+        }
+        self.update_color(pitch);
+    }
+
+    // releases the keys of the channel held by a pedal which is now up
+    fn release_pedal_held_keys(&mut self, channel: u8) {
+        let channel_bit: u16 = 1 << channel;
+        for pitch in 0..128 {
+            let still_held = ((self.sustain_channels & channel_bit) != 0) ||
+                             ((self.sostenuto_keys[pitch] & channel_bit) != 0);
+            if ((self.pedal_held_channels[pitch] & channel_bit) != 0) && !still_held {
+                self.pedal_held_channels[pitch] &= !channel_bit;
+                self.update_color(pitch as u8);
+            }
+        }
+    }
+
+    // controller is the number of the control change. Values from 64 are "on".
+    pub fn set_pedal(&mut self, channel: u8, controller: u8, value: u8) {
+        let channel_bit: u16 = 1 << channel;
+        let is_down = value >= 64;
+        match controller {
+            SUSTAIN_PEDAL => {
+                if is_down {
+                    self.sustain_channels |= channel_bit;
+                } else {
+                    self.sustain_channels &= !channel_bit;
+                    self.release_pedal_held_keys(channel);
+                }
+            }
+            SOSTENUTO_PEDAL => {
+                // the sostenuto pedal only holds the keys pressed when it goes down
+                match (is_down, (self.sostenuto_channels & channel_bit) != 0) {
+                    (true, false) => {
+                        self.sostenuto_channels |= channel_bit;
+                        for pitch in 0..128 {
+                            self.sostenuto_keys[pitch] |= self.pressed_channels[pitch] & channel_bit;
+                        }
+                    }
+                    (false, true) => {
+                        self.sostenuto_channels &= !channel_bit;
+                        for pitch in 0..128 {
+                            self.sostenuto_keys[pitch] &= !channel_bit;
+                        }
+                        self.release_pedal_held_keys(channel);
+                    }
+                    _ => (),
+                }
+            }
+            SOFT_PEDAL => {
+                if is_down {
+                    self.soft_channels |= channel_bit;
+                } else {
+                    self.soft_channels &= !channel_bit;
+                }
+            }
+            _ => (),
+        }
+    }
+
+}
+
+// where the keys of a keyboard drawn by draw_keyboard are, as their first column
+// and their width. The white keys are drawn first, under the dieses.
+struct KeyboardShape
+{
+    width: usize,
+    octave_x: usize, // the first column of do 1
+    octave_width: usize,
+    octave_keys: [(usize, usize); 12], // from do, from the start of the octave
+    lower_separators: [usize; 5], // do/re, re/mi, fa/sol, sol/la and la/si, under the dieses
+    mi_fa_separator: usize,
+    la_0: (usize, usize),
+    la_diese_0: (usize, usize),
+    si_0: (usize, usize),
+    do_8_width: usize,
+
+    // the columns of the keys as seen from above the keyboard, and whether
+    // they are dieses
+    octave_key_columns: [(usize, usize, bool); 12],
+    la_0_columns: (usize, usize),
+    la_diese_0_columns: (usize, usize),
+    si_0_columns: (usize, usize),
+    do_8_columns: (usize, usize),
 }
 
-fn draw_octave(ui: &RustBox, x: usize, y: usize, notes_color: &OctaveColor)
+const NORMAL_KEYBOARD: KeyboardShape = KeyboardShape {
+    width: 188,
+    octave_x: 8,
+    octave_width: 25,
+    octave_keys: [(0, 3), (2, 2), (3, 4), (6, 2), (7, 3), (10, 4), (13, 2), (14, 4), (17, 2), (18, 3), (21, 2), (21, 4)],
+    lower_separators: [3, 6, 14, 18, 21],
+    mi_fa_separator: 10,
+    la_0: (1, 3),
+    la_diese_0: (4, 2),
+    si_0: (4, 4),
+    do_8_width: 4,
+
+    octave_key_columns: [(0, 2, false), (2, 2, true), (4, 2, false), (6, 2, true),
+                         (8, 2, false), (10, 3, false), (13, 2, true), (15, 2, false),
+                         (17, 2, true), (19, 2, false), (21, 2, true), (23, 2, false)],
+    la_0_columns: (1, 3),
+    la_diese_0_columns: (4, 2),
+    si_0_columns: (6, 2),
+    do_8_columns: (8 + 25 * 7, 4),
+};
+
+// one column per key, for the screens too narrow for the normal keyboard
+const COMPACT_KEYBOARD: KeyboardShape = KeyboardShape {
+    width: 104,
+    octave_x: 4,
+    octave_width: 14,
+    octave_keys: [(0, 2), (2, 1), (2, 2), (4, 1), (4, 2), (6, 2), (8, 1), (8, 2), (10, 1), (10, 2), (12, 1), (12, 2)],
+    lower_separators: [2, 4, 8, 10, 12],
+    mi_fa_separator: 6,
+    la_0: (1, 1),
+    la_diese_0: (2, 1),
+    si_0: (2, 2),
+    do_8_width: 2,
+
+    octave_key_columns: [(1, 1, false), (2, 1, true), (3, 1, false), (4, 1, true),
+                         (5, 1, false), (7, 1, false), (8, 1, true), (9, 1, false),
+                         (10, 1, true), (11, 1, false), (12, 1, true), (13, 1, false)],
+    la_0_columns: (1, 1),
+    la_diese_0_columns: (2, 1),
+    si_0_columns: (3, 1),
+    do_8_columns: (4 + 14 * 7 + 1, 1),
+};
+
+// velocities holds the velocities of the twelve keys of the octave, starting from do
+fn draw_octave(canvas: &mut Canvas, shape: &KeyboardShape, x: usize, y: usize, notes_color: &OctaveColor, velocities: &[u8])
 {
-  draw_piano_key(ui, x,     y, 3, 8, notes_color.do_color as u16);  // do
-  draw_piano_key(ui, x + 3, y, 4, 8, notes_color.re_color as u16);  // re
-  draw_piano_key(ui, x + 7, y, 3, 8, notes_color.mi_color as u16);  // mi
+  let keys = &shape.octave_keys;
+  let separators = &shape.lower_separators;
 
-  draw_piano_key(ui, x + 10, y, 4, 8, notes_color.fa_color as u16); // fa
-  draw_piano_key(ui, x + 14, y, 4, 8, notes_color.sol_color as u16); // sol
-  draw_piano_key(ui, x + 18, y, 3, 8, notes_color.la_color as u16); // la
-  draw_piano_key(ui, x + 21, y, 4, 8, notes_color.si_color as u16); // si
+  draw_piano_key(canvas, x + keys[0].0, y, keys[0].1, 8, notes_color.do_color as u16, get_key_glyph(velocities[0]));  // do
+  draw_piano_key(canvas, x + keys[2].0, y, keys[2].1, 8, notes_color.re_color as u16, get_key_glyph(velocities[2]));  // re
+  draw_piano_key(canvas, x + keys[4].0, y, keys[4].1, 8, notes_color.mi_color as u16, get_key_glyph(velocities[4]));  // mi
 
-  draw_piano_key(ui, x + 2, y, 2, 5, notes_color.do_diese_color as u16);  // do#
-  draw_piano_key(ui, x + 6, y, 2, 5, notes_color.re_diese_color as u16);  // re#
+  draw_piano_key(canvas, x + keys[5].0, y, keys[5].1, 8, notes_color.fa_color as u16, get_key_glyph(velocities[5])); // fa
+  draw_piano_key(canvas, x + keys[7].0, y, keys[7].1, 8, notes_color.sol_color as u16, get_key_glyph(velocities[7])); // sol
+  draw_piano_key(canvas, x + keys[9].0, y, keys[9].1, 8, notes_color.la_color as u16, get_key_glyph(velocities[9])); // la
+  draw_piano_key(canvas, x + keys[11].0, y, keys[11].1, 8, notes_color.si_color as u16, get_key_glyph(velocities[11])); // si
 
-  draw_separating_line(ui, x + 3, y + 5, 3, notes_color.do_color as u16); // between do and re
-  draw_separating_line(ui, x + 6, y + 5, 3, notes_color.re_color as u16); // between re and mi
+  draw_piano_key(canvas, x + keys[1].0, y, keys[1].1, 5, notes_color.do_diese_color as u16, get_key_glyph(velocities[1]));  // do#
+  draw_piano_key(canvas, x + keys[3].0, y, keys[3].1, 5, notes_color.re_diese_color as u16, get_key_glyph(velocities[3]));  // re#
 
-  draw_piano_key(ui, x + 13, y, 2, 5, notes_color.fa_diese_color as u16); // fa#
-  draw_piano_key(ui, x + 17, y, 2, 5, notes_color.sol_diese_color as u16); // sol#
-  draw_piano_key(ui, x + 21, y, 2, 5, notes_color.la_diese_color as u16); // la#
+  draw_separating_line(canvas, x + separators[0], y + 5, 3, notes_color.do_color as u16); // between do and re
+  draw_separating_line(canvas, x + separators[1], y + 5, 3, notes_color.re_color as u16); // between re and mi
 
-  draw_separating_line(ui, x + 14, y + 5, 3, notes_color.fa_color as u16); // between fa and sol
-  draw_separating_line(ui, x + 18, y + 5, 3, notes_color.sol_color as u16); // between sol and la
-  draw_separating_line(ui, x + 21, y + 5, 3, notes_color.la_color as u16); // between la and si
+  draw_piano_key(canvas, x + keys[6].0, y, keys[6].1, 5, notes_color.fa_diese_color as u16, get_key_glyph(velocities[6])); // fa#
+  draw_piano_key(canvas, x + keys[8].0, y, keys[8].1, 5, notes_color.sol_diese_color as u16, get_key_glyph(velocities[8])); // sol#
+  draw_piano_key(canvas, x + keys[10].0, y, keys[10].1, 5, notes_color.la_diese_color as u16, get_key_glyph(velocities[10])); // la#
 
-  draw_separating_line(ui, x + 10, y, 8, notes_color.mi_color as u16); // between mi and fa
+  draw_separating_line(canvas, x + separators[2], y + 5, 3, notes_color.fa_color as u16); // between fa and sol
+  draw_separating_line(canvas, x + separators[3], y + 5, 3, notes_color.sol_color as u16); // between sol and la
+  draw_separating_line(canvas, x + separators[4], y + 5, 3, notes_color.la_color as u16); // between la and si
+
+  draw_separating_line(canvas, x + shape.mi_fa_separator, y, 8, notes_color.mi_color as u16); // between mi and fa
+}
+
+// the columns of a key above the keyboard drawn by draw_keyboard, from its start
+fn get_key_columns(shape: &KeyboardShape, pitch: u8) -> Option<(usize, usize, bool)> {
+    match pitch {
+        utils::LA_0 => Some((shape.la_0_columns.0, shape.la_0_columns.1, false)),
+        utils::LA_DIESE_0 => Some((shape.la_diese_0_columns.0, shape.la_diese_0_columns.1, true)),
+        utils::SI_0 => Some((shape.si_0_columns.0, shape.si_0_columns.1, false)),
+        p if (p >= utils::DO_1) && (p < utils::DO_8) => {
+            let (column, width, is_diese) = shape.octave_key_columns[((p - utils::DO_1) % 12) as usize];
+            Some((shape.octave_x + shape.octave_width * ((p - utils::DO_1) / 12) as usize + column, width, is_diese))
+        }
+        utils::DO_8 => Some((shape.do_8_columns.0, shape.do_8_columns.1, false)),
+        _ => None,
+    }
 }
 
-fn draw_keyboard(ui: &RustBox, keyboard: &KeysColor, pos_x: usize, pos_y: usize)
+fn draw_keyboard(canvas: &mut Canvas, keyboard: &KeysColor, shape: &KeyboardShape, pos_x: usize, pos_y: usize)
 {
-  draw_piano_key(ui, pos_x + 1, pos_y, 3, 8, keyboard.la_0_color as u16); // la 0
-  draw_piano_key(ui, pos_x + 4, pos_y, 4, 8, keyboard.si_0_color as u16); // si 0
-  draw_piano_key(ui, pos_x + 4, pos_y, 2, 5, keyboard.la_diese_0_color as u16); // la# 0
-  draw_separating_line(ui, pos_x + 4, pos_y + 5, 3, keyboard.la_0_color as u16); // between la0 and si0
+  draw_piano_key(canvas, pos_x + shape.la_0.0, pos_y, shape.la_0.1, 8, keyboard.la_0_color as u16, get_key_glyph(keyboard.velocities[utils::LA_0 as usize])); // la 0
+  draw_piano_key(canvas, pos_x + shape.si_0.0, pos_y, shape.si_0.1, 8, keyboard.si_0_color as u16, get_key_glyph(keyboard.velocities[utils::SI_0 as usize])); // si 0
+  draw_piano_key(canvas, pos_x + shape.la_diese_0.0, pos_y, shape.la_diese_0.1, 5, keyboard.la_diese_0_color as u16, get_key_glyph(keyboard.velocities[utils::LA_DIESE_0 as usize])); // la# 0
+  draw_separating_line(canvas, pos_x + shape.la_diese_0.0, pos_y + 5, 3, keyboard.la_0_color as u16); // between la0 and si0
 
   for i in 0 .. 7  {
-    draw_octave(ui, pos_x + 8 + (25 * i), pos_y, &keyboard.octaves[i]);
+    let octave_start = utils::DO_1 as usize + 12 * i;
+    draw_octave(canvas, shape, pos_x + shape.octave_x + (shape.octave_width * i), pos_y, &keyboard.octaves[i], &keyboard.velocities[octave_start .. octave_start + 12]);
   }
 
-  draw_piano_key(ui, pos_x + 8 + (25 * 7), pos_y, 4, 8, keyboard.do_8_color as u16); // do 8
+  draw_piano_key(canvas, pos_x + shape.octave_x + (shape.octave_width * 7), pos_y, shape.do_8_width, 8, keyboard.do_8_color as u16, get_key_glyph(keyboard.velocities[utils::DO_8 as usize])); // do 8
 
   for i in 0 .. 7  {
-    draw_separating_line(ui, pos_x + 8 + (25 * (i + 1)), pos_y, 8, keyboard.octaves[i].si_color as u16); // between octaves
+    draw_separating_line(canvas, pos_x + shape.octave_x + (shape.octave_width * (i + 1)), pos_y, 8, keyboard.octaves[i].si_color as u16); // between octaves
   }
 
-  draw_separating_line(ui, pos_x + 8 + (25 * 0), pos_y, 8, keyboard.si_0_color as u16);
+  draw_separating_line(canvas, pos_x + shape.octave_x, pos_y, 8, keyboard.si_0_color as u16);
 
 }
 
-fn play_music(midi_out: &mut midir::MidiOutputConnection, event: &[utils::MidiMessage])
+const ALL_SOUND_OFF: u8 = 120;
+const RESET_ALL_CONTROLLERS: u8 = 121;
+const ALL_NOTES_OFF: u8 = 123;
+
+// the midi output, which keeps track of the notes sounding on each channel so
+// that they can be stopped at any time. They are stopped when the synth is
+// dropped, which includes quitting on a panic.
+struct Synth
+{
+    conn_out: midir::MidiOutputConnection,
+    sounding_notes: [[u8; 128]; 16], // velocity of the notes sounding on each channel, 0 if not sounding
+}
+
+impl Synth
 {
-    for message in event.iter() {
-        if let Err(e) = midi_out.send(&message) {
+    pub fn new(conn_out: midir::MidiOutputConnection) -> Self
+    {
+        Synth {
+            conn_out: conn_out,
+            sounding_notes: [[0; 128]; 16],
+        }
+    }
+
+    fn send(&mut self, message: &[u8]) {
+        if let Err(e) = self.conn_out.send(message) {
             println!("Error occured while playing some event: {}", e.description());
             if let Some(e) = e.cause() {
                 println!("{}", e.description());
             }
         }
     }
+
+    pub fn play(&mut self, event: &[utils::MidiMessage]) {
+        for message in event.iter() {
+            self.send(message);
+
+            let channel = (message[0] & 0x0F) as usize;
+            match (message[0] & 0xF0, message.len()) {
+                (0x90, 3) if (message[1] < 0x80) => self.sounding_notes[channel][message[1] as usize] = message[2],
+                (0x80, 3) if (message[1] < 0x80) => self.sounding_notes[channel][message[1] as usize] = 0,
+                (0xB0, 3) if (message[1] == ALL_SOUND_OFF) || (message[1] == ALL_NOTES_OFF) => {
+                    self.sounding_notes[channel] = [0; 128]
+                }
+                _ => (),
+            }
+        }
+    }
+
+    // stops the sounding notes. They are still considered sounding, to be
+    // played again when resuming.
+    pub fn pause(&mut self) {
+        for channel in 0..16 {
+            for pitch in 0..128 {
+                if self.sounding_notes[channel][pitch] != 0 {
+                    self.send(&[0x80 | channel as u8, pitch as u8, 0]);
+                }
+            }
+
+            // in case some notes are not known (e.g. held by a pedal)
+            self.send(&[0xB0 | channel as u8, ALL_NOTES_OFF, 0]);
+            self.send(&[0xB0 | channel as u8, ALL_SOUND_OFF, 0]);
+        }
+    }
+
+    // plays again the notes stopped by pause
+    pub fn resume(&mut self) {
+        for channel in 0..16 {
+            for pitch in 0..128 {
+                let velocity = self.sounding_notes[channel][pitch];
+                if velocity != 0 {
+                    self.send(&[0x90 | channel as u8, pitch as u8, velocity]);
+                }
+            }
+        }
+    }
+
+    // stops everything, then sends the state messages and plays the sounding
+    // notes, as given by get_synth_state. In pause, the notes are only
+    // considered sounding, to be played when resuming.
+    pub fn seek(&mut self, state_messages: &[utils::MidiMessage], sounding_notes: [[u8; 128]; 16], is_in_pause: bool) {
+        self.pause();
+        for message in state_messages.iter() {
+            self.send(message);
+        }
+
+        self.sounding_notes = sounding_notes;
+        if !is_in_pause {
+            self.resume();
+        }
+    }
 }
 
-fn init_ref_pos(width: usize, height: usize) -> (usize, usize) {
-    let keyboard_height = 8;
-    let keyboard_width = 188;
+impl Drop for Synth
+{
+    fn drop(&mut self) {
+        self.pause();
+        self.sounding_notes = [[0; 128]; 16];
+    }
+}
+
+const DATA_ENTRY_MSB: u8 = 6;
+const DATA_ENTRY_LSB: u8 = 38;
+const DATA_INCREMENT: u8 = 96;
+const DATA_DECREMENT: u8 = 97;
+const NRPN_LSB: u8 = 98;
+const NRPN_MSB: u8 = 99;
+const RPN_LSB: u8 = 100;
+const RPN_MSB: u8 = 101;
+
+// the registered or non registered parameter selected on a channel, as the
+// controller of its msb (RPN_MSB or NRPN_MSB), its msb and its lsb
+type Parameter = (u8, u8, u8);
+
+// returns the messages setting the synth as it is after playing song[0..index]
+// (programs, controllers, parameters, pitch bends and channel pressures), and
+// the velocity of the notes still sounding at that point on each channel.
+//
+// The parameters (e.g. the pitch bend range) are set again in the order they
+// were first set in the song, each one by selecting it before entering its
+// value. The parameter selected in the song is selected again afterwards.
+fn get_synth_state(song: &utils::Song, index: usize) -> (Vec<utils::MidiMessage>, [[u8; 128]; 16]) {
+    let mut programs: [Option<u8>; 16] = [None; 16];
+    let mut has_program_change = [false; 16]; // anywhere in the song
+    let mut controllers: [[Option<u8>; 120]; 16] = [[None; 120]; 16];
+    let mut selected_parameters: [Option<Parameter>; 16] = [None; 16];
+    let mut parameter_values: Vec<Vec<(Parameter, [Option<u8>; 2])>> = vec![Vec::new(); 16];
+    let mut pitch_bends: [Option<(u8, u8)>; 16] = [None; 16];
+    let mut channel_pressures: [Option<u8>; 16] = [None; 16];
+    let mut sounding_notes = [[0; 128]; 16];
+
+    for (event_index, event) in song.iter().enumerate() {
+        for message in event.midi_messages.iter() {
+            if (message[0] < 0x80) || (message[0] >= 0xF0) {
+                continue;
+            }
+
+            let channel = (message[0] & 0x0F) as usize;
+            if (message[0] & 0xF0) == 0xC0 {
+                has_program_change[channel] = true;
+            }
+
+            if event_index >= index {
+                continue;
+            }
+
+            match (message[0] & 0xF0, message.len()) {
+                (0x90, 3) if (message[1] < 0x80) => sounding_notes[channel][message[1] as usize] = message[2],
+                (0x80, 3) if (message[1] < 0x80) => sounding_notes[channel][message[1] as usize] = 0,
+                (0xB0, 3) if message[1] == RESET_ALL_CONTROLLERS => {
+                    controllers[channel] = [None; 120];
+                    selected_parameters[channel] = None;
+                    pitch_bends[channel] = None;
+                    channel_pressures[channel] = None;
+                }
+                (0xB0, 3) if (message[1] == ALL_SOUND_OFF) || (message[1] == ALL_NOTES_OFF) => {
+                    sounding_notes[channel] = [0; 128]
+                }
+                (0xB0, 3) if (message[1] >= NRPN_LSB) && (message[1] <= RPN_MSB) => {
+                    // selecting a registered parameter deselects the non
+                    // registered one, and the other way around
+                    let msb_controller = if message[1] >= RPN_LSB { RPN_MSB } else { NRPN_MSB };
+                    let (msb, lsb) = match selected_parameters[channel] {
+                        Some((controller, msb, lsb)) if controller == msb_controller => (msb, lsb),
+                        _ => (127, 127),
+                    };
+                    selected_parameters[channel] = Some(if message[1] == msb_controller {
+                        (msb_controller, message[2], lsb)
+                    } else {
+                        (msb_controller, msb, message[2])
+                    });
+                }
+                (0xB0, 3) if (message[1] == DATA_ENTRY_MSB) || (message[1] == DATA_ENTRY_LSB) => {
+                    // the values entered with no parameter selected are ignored
+                    if let Some(parameter) = selected_parameters[channel] {
+                        if (parameter.1, parameter.2) != (127, 127) {
+                            let values = &mut parameter_values[channel];
+                            let pos = match values.iter().position(|x| x.0 == parameter) {
+                                Some(pos) => pos,
+                                None => {
+                                    values.push((parameter, [None, None]));
+                                    values.len() - 1
+                                }
+                            };
+                            let byte_index = if message[1] == DATA_ENTRY_MSB { 0 } else { 1 };
+                            values[pos].1[byte_index] = Some(message[2]);
+                        }
+                    }
+                }
+                // the increments and decrements only make sense once
+                (0xB0, 3) if (message[1] == DATA_INCREMENT) || (message[1] == DATA_DECREMENT) => (),
+                (0xB0, 3) if (message[1] < 120) => controllers[channel][message[1] as usize] = Some(message[2]),
+                (0xC0, 2) => programs[channel] = Some(message[1]),
+                (0xD0, 2) => channel_pressures[channel] = Some(message[1]),
+                (0xE0, 3) => pitch_bends[channel] = Some((message[1], message[2])),
+                _ => (),
+            }
+        }
+    }
+
+    let mut state_messages = Vec::new();
+    for channel in 0..16 {
+        let status = channel as u8;
+        state_messages.push(vec![0xB0 | status, RESET_ALL_CONTROLLERS, 0]);
+
+        // the bank selects (controllers 0 and 32) come before the program change
+        for controller in 0..120 {
+            if let Some(value) = controllers[channel][controller] {
+                state_messages.push(vec![0xB0 | status, controller as u8, value]);
+            }
+        }
+
+        for &((msb_controller, msb, lsb), values) in parameter_values[channel].iter() {
+            state_messages.push(vec![0xB0 | status, msb_controller, msb]);
+            state_messages.push(vec![0xB0 | status, msb_controller - 1, lsb]);
+            if let Some(value) = values[0] {
+                state_messages.push(vec![0xB0 | status, DATA_ENTRY_MSB, value]);
+            }
+            if let Some(value) = values[1] {
+                state_messages.push(vec![0xB0 | status, DATA_ENTRY_LSB, value]);
+            }
+        }
+
+        // no parameter is selected after a reset
+        let selected_parameter = match selected_parameters[channel] {
+            None if !parameter_values[channel].is_empty() => Some((RPN_MSB, 127, 127)),
+            x => x,
+        };
+        if let Some((msb_controller, msb, lsb)) = selected_parameter {
+            state_messages.push(vec![0xB0 | status, msb_controller, msb]);
+            state_messages.push(vec![0xB0 | status, msb_controller - 1, lsb]);
+        }
+
+        // a program only changed later in the song is set back to the default one
+        match programs[channel] {
+            Some(program) => state_messages.push(vec![0xC0 | status, program]),
+            None if has_program_change[channel] => state_messages.push(vec![0xC0 | status, 0]),
+            None => (),
+        }
+
+        if let Some((lsb, msb)) = pitch_bends[channel] {
+            state_messages.push(vec![0xE0 | status, lsb, msb]);
+        }
+
+        if let Some(pressure) = channel_pressures[channel] {
+            state_messages.push(vec![0xD0 | status, pressure]);
+        }
+    }
+
+    (state_messages, sounding_notes)
+}
+
+const KEYBOARD_HEIGHT: usize = 8;
+
+// where the keyboard is drawn on the screen. The compact keyboard is used when
+// the normal one does not fit, and only the columns from first_column are
+// shown when the compact one does not fit either. They are centred on the
+// pitches played, and can be scrolled by an octave at a time.
+struct KeyboardLayout
+{
+    shape: &'static KeyboardShape,
+    ref_x: usize,
+    ref_y: usize,
+    first_column: usize,
+    nb_columns: usize,
+}
+
+impl KeyboardLayout
+{
+    pub fn new(width: usize, height: usize, pitch_range: (u8, u8)) -> Self
+    {
+        let shape = if width >= NORMAL_KEYBOARD.width { &NORMAL_KEYBOARD } else { &COMPACT_KEYBOARD };
+        let nb_columns = std::cmp::min(width, shape.width);
+
+        let (lowest_pitch, highest_pitch) = pitch_range;
+        let (start, _, _) = get_key_columns(shape, lowest_pitch).unwrap_or((0, 0, false));
+        let (end, end_width, _) = get_key_columns(shape, highest_pitch).unwrap_or((shape.width, 0, false));
+        let center = (start + end + end_width) / 2;
+
+        KeyboardLayout {
+            shape: shape,
+            ref_x: (width - nb_columns) / 2,
+            ref_y: if height > KEYBOARD_HEIGHT { (height - KEYBOARD_HEIGHT) / 2 } else { 0 },
+            first_column: std::cmp::min(center.saturating_sub(nb_columns / 2), shape.width - nb_columns),
+            nb_columns: nb_columns,
+        }
+    }
 
-    let ref_x = if width > keyboard_width { (width - keyboard_width) / 2 } else { 0 };
-    let ref_y = if height > keyboard_height { (height - keyboard_height) / 2 } else { 0 };
+    pub fn scroll(&mut self, to_the_right: bool) {
+        self.first_column = if to_the_right {
+            std::cmp::min(self.first_column + self.shape.octave_width, self.shape.width - self.nb_columns)
+        } else {
+            self.first_column.saturating_sub(self.shape.octave_width)
+        };
+    }
+}
 
-    (ref_x, ref_y)
+// the lowest and highest pitches of the notes on the keyboard, the whole
+// keyboard if there is none
+fn get_pitch_range(notes: &[Note]) -> (u8, u8) {
+    let pitches = notes.iter().map(|x| x.pitch).filter(|&x| (x >= utils::LA_0) && (x <= utils::DO_8));
+    match (pitches.clone().min(), pitches.max()) {
+        (Some(lowest_pitch), Some(highest_pitch)) => (lowest_pitch, highest_pitch),
+        _ => (utils::LA_0, utils::DO_8),
+    }
 }
 
-fn update_keyboard(keyboard: &mut KeysColor, key_events: &[KeyData]) {
+// the pedals are updated after the keys pressed or released at the same time
+fn update_keyboard(keyboard: &mut KeysColor, key_events: &[KeyEvent], midi_messages: &[utils::MidiMessage]) {
     for k_ev in key_events {
-        match *k_ev {
-            KeyData::Pressed(pitch) => keyboard.set_color(pitch),
-            KeyData::Released(pitch) => keyboard.reset_color(pitch),
+        match k_ev.data {
+            KeyData::Pressed(pitch, velocity) => keyboard.press_key(pitch, k_ev.channel, velocity),
+            KeyData::Released(pitch) => keyboard.release_key(pitch, k_ev.channel),
+        }
+    }
+
+    for message in midi_messages {
+        if (message.len() == 3) && ((message[0] & 0xF0) == 0xB0) {
+            keyboard.set_pedal(message[0] & 0x0F, message[1], message[2]);
         }
     }
 }
 
-fn update_screen(ui: &RustBox, keyboard: &KeysColor, ref_x: usize, ref_y: usize)
+// the pedals down on any channel are highlighted
+fn draw_pedals(canvas: &mut Canvas, keyboard: &KeysColor, x: usize, y: usize)
 {
-    ui.clear();
-    draw_keyboard(ui, keyboard, ref_x, ref_y);
-    ui.print(ref_x, ref_y + 10, rustbox::RB_BOLD, rustbox::Color::Magenta, rustbox::Color::Default, "press <CTRL + q> to quit");
-    ui.print(ref_x, ref_y + 11, rustbox::RB_BOLD, rustbox::Color::Magenta, rustbox::Color::Default, "press <space> to pause/unpause");
-    ui.present();
+    let pedals = [("sustain", keyboard.sustain_channels),
+                  ("sostenuto", keyboard.sostenuto_channels),
+                  ("soft", keyboard.soft_channels)];
+
+    let mut pos_x = x;
+    for &(name, channels) in pedals.iter() {
+        let style = match channels {
+            0 => rustbox::RB_NORMAL,
+            _ => rustbox::RB_REVERSE,
+        };
+        canvas.print(pos_x, y, &format!(" {} ", name), rustbox::Color::Default.as_16color() | style.bits(), rustbox::Color::Default.as_16color());
+        pos_x += name.len() + 3;
+    }
 }
 
-pub fn play(song: utils::Song, midi_output_port: u32) {
-    let mut exit_requested = false;
+// song information displayed above the keyboard
+struct SongInfo
+{
+    title: String,
+    tempo: String,
+    time_signature: String,
+    key_signature: String,
+    position: String, // bar and beat
+    speed: String,
+    loop_section: String,
+    text: String, // last lyric, marker, ...
+}
 
-    let midi_out = midir::MidiOutput::new("Midi output from pianoterm-rs");
-    if let Err(e) = midi_out {
-        println!("Error occured while initialising the midi output: {}", e.description());
-        return ();
+impl SongInfo
+{
+    pub fn new(title: String) -> Self
+    {
+        SongInfo {
+            title: title,
+            tempo: String::new(),
+            time_signature: String::new(),
+            key_signature: String::new(),
+            position: String::new(),
+            speed: String::new(),
+            loop_section: String::new(),
+            text: String::new(),
+        }
     }
 
-    let midi_out = midi_out.unwrap();
-    let conn_out = midi_out.connect(midi_output_port, "output midi port from pianoterm-rs");
+    pub fn update(&mut self, meta_events: &[MetaEvent]) {
+        for meta in meta_events {
+            match *meta {
+                MetaEvent::Tempo(_) => self.tempo = meta.to_string(),
+                MetaEvent::TimeSignature { .. } => self.time_signature = meta.to_string(),
+                MetaEvent::KeySignature { .. } => self.key_signature = meta.to_string(),
+                MetaEvent::Text(_)
+                | MetaEvent::Lyric(_)
+                | MetaEvent::Marker(_)
+                | MetaEvent::CuePoint(_) => self.text = meta.to_string(),
+                _ => (),
+            }
+        }
+    }
 
-    if let Err(e) = conn_out {
-        println!("Failed to open midi output port: {}", e.kind().description());
-        return ();
+    pub fn set_position(&mut self, tempo_map: &TempoMap, time_in_ns: u64) {
+        // bars and beats are numbered from 1 for display
+        let (bar, beat) = tempo_map.get_bar_and_beat(tempo_map.get_ticks(time_in_ns));
+        self.position = format!("bar {} beat {}", bar + 1, beat + 1);
     }
 
-    let mut conn_out = conn_out.unwrap();
-    let ui = RustBox::init(Default::default());
-    if let Err(e) = ui {
-        println!("Failed to initialise the user interface (rustbox): {}", e.description());
-        return ();
-    };
+    pub fn set_speed(&mut self, speed_percent: u32) {
+        self.speed = format!("speed {}%", speed_percent);
+    }
 
-    let ui = ui.unwrap();
-    let (mut x, mut y) = init_ref_pos(ui.width(), ui.height());
+    pub fn set_loop(&mut self, start_in_ns: Option<u64>, end_in_ns: Option<u64>) {
+        let to_seconds = |x: u64| x as f64 / 1_000_000_000.0;
+        self.loop_section = match (start_in_ns, end_in_ns) {
+            (Some(start), Some(end)) => format!("loop {:.1}s - {:.1}s", to_seconds(start), to_seconds(end)),
+            (Some(start), None) => format!("loop from {:.1}s", to_seconds(start)),
+            (None, Some(end)) => format!("loop until {:.1}s", to_seconds(end)),
+            (None, None) => String::new(),
+        };
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        let signatures = [&self.position, &self.speed, &self.loop_section, &self.tempo, &self.time_signature, &self.key_signature]
+            .iter()
+            .filter(|x| !x.is_empty())
+            .map(|x| x.as_str())
+            .collect::<Vec<&str>>()
+            .join(" - ");
+
+        vec![self.title.clone(), signatures, self.text.clone()]
+    }
+}
+
+const ROLL_ROW_IN_NS: u64 = 150_000_000;
+
+// the notes coming next, falling onto the keyboard one row at a time
+struct PianoRoll
+{
+    notes: Vec<Note>,
+    position_in_ns: u64, // the time of the row just above the keyboard
+}
+
+impl PianoRoll
+{
+    pub fn new(notes: Vec<Note>) -> Self
+    {
+        PianoRoll {
+            notes: notes,
+            position_in_ns: 0,
+        }
+    }
+
+    pub fn set_position(&mut self, time_in_ns: u64) {
+        self.position_in_ns = time_in_ns;
+    }
+}
+
+// draws the notes held in the rows between top_y and the keyboard, at ref_y
+fn draw_piano_roll(canvas: &mut Canvas, shape: &KeyboardShape, piano_roll: &PianoRoll, ref_x: usize, ref_y: usize, top_y: usize)
+{
+    if ref_y <= top_y {
+        return;
+    }
+
+    let start_in_ns = piano_roll.position_in_ns;
+    let end_in_ns = start_in_ns + (ref_y - top_y) as u64 * ROLL_ROW_IN_NS;
+    for note in piano_roll.notes.iter().filter(|x| (x.start_ns < end_in_ns) && (x.end_ns > start_in_ns)) {
+        if let Some((column, width, is_diese)) = get_key_columns(shape, note.pitch) {
+            let (normal_key_color, diese_key_color) = CHANNEL_COLORS[note.channel as usize % CHANNEL_COLORS.len()];
+            let color = if is_diese { diese_key_color } else { normal_key_color };
+
+            let first_row = (std::cmp::max(note.start_ns, start_in_ns) - start_in_ns) / ROLL_ROW_IN_NS;
+            let last_row = (std::cmp::min(note.end_ns, end_in_ns) - 1 - start_in_ns) / ROLL_ROW_IN_NS;
+            for row in first_row..last_row + 1 {
+                draw_piano_key(canvas, ref_x + column, ref_y - 1 - row as usize, width, 1, color.as_16color(), get_key_glyph(note.velocity));
+            }
+        }
+    }
+}
+
+fn get_song_notes(song: &utils::Song) -> Vec<Note> {
+    let key_events: Vec<KeyEvent> = song.iter().flat_map(|x| x.key_events.iter().cloned()).collect();
+    let song_length_in_ns = song.last().map_or(0, |x| x.time_in_ns);
+    keyboard_events_extractor::get_notes_from_key_events(&key_events, song_length_in_ns)
+}
 
-    let mut keyboard = KeysColor::new();
+// the title of a song is made of its track names and copyright notices
+fn get_song_title(song: &utils::Song) -> String {
+    song.iter()
+        .flat_map(|x| x.meta_events.iter())
+        .filter(|x| match **x {
+                    MetaEvent::TrackName(ref text) | MetaEvent::Copyright(ref text) => !text.trim().is_empty(),
+                    _ => false,
+                })
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>()
+        .join(" - ")
+}
+
+const PLAYER_HELP: [&'static str; 7] = ["press <CTRL + q> to quit",
+                                         "press <space> to pause/unpause",
+                                         "press <left>/<right> to go 5 seconds back/forward, <down>/<up> to go to the previous/next bar",
+                                         "press <home> to go to the start, <0> to <9> to go to 0% to 90% of the song",
+                                         "press <+>/<-> to play faster/slower",
+                                         "press <a>/<b> to mark the start/end of a loop, <c> to clear it",
+                                         "press <[>/<]> to scroll the keyboard left/right when it does not fit"];
+
+const LIVE_HELP: [&'static str; 2] = ["press <CTRL + q> to quit",
+                                      "press <[>/<]> to scroll the keyboard left/right when it does not fit"];
+
+// the information is drawn at the top of the screen when there is a piano
+// roll, which takes the space left above the keyboard
+fn update_screen(canvas: &mut Canvas,
+                 keyboard: &KeysColor,
+                 layout: &KeyboardLayout,
+                 info: &[String],
+                 help: &[&str],
+                 piano_roll: Option<&PianoRoll>)
+{
+    let (ref_x, ref_y) = (layout.ref_x, layout.ref_y);
+    canvas.clear();
+    if ref_y > info.len() {
+        let info_y = match piano_roll {
+            Some(_) => 0,
+            None => ref_y - 1 - info.len(),
+        };
+        for (i, line) in info.iter().enumerate() {
+            canvas.print(ref_x, info_y + i, line, rustbox::Color::Default.as_16color() | rustbox::RB_BOLD.bits(), rustbox::Color::Default.as_16color());
+        }
+    }
+    {
+        // the keys and the notes falling onto them are drawn in the visible columns
+        let mut keyboard_view = Viewport::new(canvas, ref_x, layout.first_column, layout.nb_columns);
+        if let Some(piano_roll) = piano_roll {
+            draw_piano_roll(&mut keyboard_view, layout.shape, piano_roll, 0, ref_y, info.len() + 1);
+        }
+        draw_keyboard(&mut keyboard_view, keyboard, layout.shape, 0, ref_y);
+    }
+    draw_pedals(canvas, keyboard, ref_x, ref_y + 8);
+    for (i, line) in help.iter().enumerate() {
+        canvas.print(ref_x, ref_y + 10 + i, line, rustbox::Color::Magenta.as_16color() | rustbox::RB_BOLD.bits(), rustbox::Color::Default.as_16color());
+    }
+    canvas.present();
+}
+
+const SEEK_STEP_IN_NS: u64 = 5_000_000_000;
+
+pub const MIN_SPEED_PERCENT: u32 = 25;
+pub const MAX_SPEED_PERCENT: u32 = 200;
+const SPEED_STEP_PERCENT: u32 = 5;
+
+#[derive(Clone, Copy)]
+pub enum LoopSection {
+    Time { start_in_ns: u64, end_in_ns: u64 },
+    Bars { first_bar: u64, last_bar: u64 }, // bars start at 0, the last one is played
+}
+
+#[derive(Clone, Copy)]
+pub struct PlayOptions {
+    // draw the keys only held by a pedal in their own color
+    pub show_pedal_held_keys: bool,
+    // the initial playback speed, from MIN_SPEED_PERCENT to MAX_SPEED_PERCENT
+    pub speed_percent: u32,
+    // the section of each song to repeat until the loop is cleared
+    pub loop_section: Option<LoopSection>,
+    // added to the speed each time the loop starts again
+    pub loop_speed_up_percent: u32,
+}
+
+// plays one song. Returns false if the user asked to quit. speed_percent is
+// the playback speed, kept when changed by the user.
+fn play_song(ui: &mut RustBox,
+             synth: &mut Synth,
+             song: &utils::Song,
+             tempo_map: &TempoMap,
+             title: String,
+             options: &PlayOptions,
+             speed_percent: &mut u32)
+             -> bool {
+    let mut exit_requested = false;
     let nb_events = song.len();
-    for i in 0 .. nb_events {
+    let song_length_in_ns = song.last().map_or(0, |x| x.time_in_ns);
+
+    // the loop is only active when its start is before its end
+    let get_bar_time_in_ns = |bar| tempo_map.get_time_in_ns(tempo_map.get_bar_ticks(bar));
+    let (mut loop_start_in_ns, mut loop_end_in_ns) = match options.loop_section {
+        Some(LoopSection::Time { start_in_ns, end_in_ns }) => (Some(start_in_ns), Some(end_in_ns)),
+        Some(LoopSection::Bars { first_bar, last_bar }) => (Some(get_bar_time_in_ns(first_bar)), Some(get_bar_time_in_ns(last_bar + 1))),
+        None => (None, None),
+    };
+    loop_end_in_ns = loop_end_in_ns.map(|x| std::cmp::min(x, song_length_in_ns));
 
-        let current_event = &song[i];
-        update_keyboard(&mut keyboard, &current_event.key_events);
-        update_screen(&ui, &keyboard, x, y);
-        play_music(&mut conn_out, &current_event.midi_messages);
+    let mut song_info = SongInfo::new(title.clone());
+    song_info.set_speed(*speed_percent);
+    song_info.set_loop(loop_start_in_ns, loop_end_in_ns);
 
-        if i != nb_events - 1 {
-            let time_to_wait = song[i + 1].time_in_ns - current_event.time_in_ns;
-            let one_billion = 1_000_000_000;
-            let time_to_wait = std::time::Duration::new(time_to_wait / one_billion, (time_to_wait % one_billion) as u32);
+    let mut keyboard = KeysColor::new(options.show_pedal_held_keys);
+    let mut piano_roll = PianoRoll::new(get_song_notes(song));
 
-            let started_time = std::time::Instant::now();
-            let mut is_in_pause = false;
+    let pitch_range = get_pitch_range(&piano_roll.notes);
+    let mut layout = KeyboardLayout::new(ui.width(), ui.height(), pitch_range);
 
-            loop {
-                if EXIT_REQUESTED_BY_SIGNAL.load(Ordering::Relaxed) {
-                    EXIT_REQUESTED_BY_SIGNAL.store(false, Ordering::Relaxed);
-                    exit_requested = true;
-                }
+    let mut is_in_pause = false;
+    let mut was_in_pause = false;
 
-                if PAUSE_REQUESTED_BY_SIGNAL.load(Ordering::Relaxed) {
-                    PAUSE_REQUESTED_BY_SIGNAL.store(false, Ordering::Relaxed);
-                    is_in_pause = true;
-                }
+    // the next event to play, and the position in the song when starting to wait for it
+    let mut i = 0;
+    let mut position_in_ns = 0;
+    let mut scheduler = Scheduler::new(SystemClock::new(), *speed_percent);
+
+    // the song starts with the loop given in the options, if any
+    let mut seek_position_in_ns: Option<u64> = loop_start_in_ns;
+
+    song_info.set_position(tempo_map, position_in_ns);
+    piano_roll.set_position(position_in_ns);
+    update_screen(ui, &keyboard, &layout, &song_info.lines(), &PLAYER_HELP, Some(&piano_roll));
+
+    while i < nb_events {
+        loop {
+            if EXIT_REQUESTED_BY_SIGNAL.load(Ordering::Relaxed) {
+                EXIT_REQUESTED_BY_SIGNAL.store(false, Ordering::Relaxed);
+                exit_requested = true;
+            }
+
+            if PAUSE_REQUESTED_BY_SIGNAL.load(Ordering::Relaxed) {
+                PAUSE_REQUESTED_BY_SIGNAL.store(false, Ordering::Relaxed);
+                is_in_pause = true;
+            }
+
+            if CONTINUE_REQUESTED_BY_SIGNAL.load(Ordering::Relaxed) {
+                CONTINUE_REQUESTED_BY_SIGNAL.store(false, Ordering::Relaxed);
+                is_in_pause = false;
+            }
 
-                if CONTINUE_REQUESTED_BY_SIGNAL.load(Ordering::Relaxed) {
-                    CONTINUE_REQUESTED_BY_SIGNAL.store(false, Ordering::Relaxed);
-                    is_in_pause = false;
+            if exit_requested {
+                return false;
+            }
+
+            // the sounding notes are stopped during the pause
+            if is_in_pause != was_in_pause {
+                if is_in_pause {
+                    synth.pause();
+                } else {
+                    synth.resume();
                 }
+                scheduler.set_pause(is_in_pause);
+                was_in_pause = is_in_pause;
+            }
+
+            // the end of the loop may be reached before the next event
+            let loop_end_to_reach_in_ns = match (loop_start_in_ns, loop_end_in_ns) {
+                (Some(start), Some(end)) if (start < end) && (position_in_ns < end) && (end <= song[i].time_in_ns) => Some(end),
+                _ => None,
+            };
+            let next_position_in_ns = loop_end_to_reach_in_ns.unwrap_or(song[i].time_in_ns);
 
-                if exit_requested {
-                    return;
+            if seek_position_in_ns.is_some() {
+                break;
+            }
+
+            if scheduler.get_time_to_wait_in_ns(next_position_in_ns) == Some(0) {
+                if loop_end_to_reach_in_ns.is_some() {
+                    *speed_percent = std::cmp::min(speed_percent.saturating_add(options.loop_speed_up_percent), MAX_SPEED_PERCENT);
+                    scheduler.set_speed(*speed_percent);
+                    seek_position_in_ns = loop_start_in_ns;
                 }
+                break;
+            }
 
-                let time_now = std::time::Instant::now();
-                let waited_time = time_now - started_time;
+            // the piano roll scrolls by one row at a time, also in between the events
+            let current_position_in_ns = std::cmp::min(scheduler.get_position_in_ns(), next_position_in_ns);
+            if current_position_in_ns / ROLL_ROW_IN_NS != piano_roll.position_in_ns / ROLL_ROW_IN_NS {
+                song_info.set_position(tempo_map, current_position_in_ns);
+                piano_roll.set_position(current_position_in_ns);
+                update_screen(ui, &keyboard, &layout, &song_info.lines(), &PLAYER_HELP, Some(&piano_roll));
+            }
+            let next_row_position_in_ns = (current_position_in_ns / ROLL_ROW_IN_NS + 1) * ROLL_ROW_IN_NS;
 
-                if (!is_in_pause) && (waited_time > time_to_wait) {
-                    break;
+            let time_to_sleep = match scheduler.get_time_to_wait_in_ns(std::cmp::min(next_position_in_ns, next_row_position_in_ns)) {
+                Some(time_to_wait_in_ns) => {
+                    std::cmp::min(std::time::Duration::from_millis(100),
+                                  std::time::Duration::new(time_to_wait_in_ns / 1_000_000_000, (time_to_wait_in_ns % 1_000_000_000) as u32))
                 }
+                None => std::time::Duration::from_millis(100),
+            };
 
-                let time_to_sleep = {
-                    if time_to_wait > waited_time {
-                        std::cmp::min(std::time::Duration::from_millis(100), time_to_wait - waited_time)
-                    } else {
-                        std::time::Duration::from_millis(100)
+            match ui.peek_event(time_to_sleep, false) {
+                Ok(Event::NoEvent)
+                | Ok(Event::MouseEvent(_, _, _)) => (),
+                Ok(Event::KeyEventRaw(_, _, _)) => panic!("Raw event received, whereas the raw parameter to peek_event was set to false!"),
+                Ok(Event::ResizeEvent(w, h)) => {
+                    if (w < 0) || (h < 0) {
+                        panic!("new window size has negtive components. Can't happen after a successful init!");
                     }
-                };
-
-                match ui.peek_event(time_to_sleep, false) {
-                    Ok(Event::NoEvent)
-                    | Ok(Event::MouseEvent(_, _, _)) => (),
-                    Ok(Event::KeyEventRaw(_, _, _)) => panic!("Raw event received, whereas the raw parameter to peek_event was set to false!"),
-                    Ok(Event::ResizeEvent(w, h)) => {
-                        if (w < 0) || (h < 0) {
-                            panic!("new window size has negtive components. Can't happen after a successful init!");
+                    layout = KeyboardLayout::new(w as usize, h as usize, pitch_range);
+                    update_screen(ui, &keyboard, &layout, &song_info.lines(), &PLAYER_HELP, Some(&piano_roll));
+                },
+                Ok(Event::KeyEvent(key)) => {
+                    let current_position_in_ns = std::cmp::min(scheduler.get_position_in_ns(), song[i].time_in_ns);
+                    let (current_bar, _) = tempo_map.get_bar_and_beat(tempo_map.get_ticks(current_position_in_ns));
+
+                    match key {
+                        Key::Ctrl('q') => exit_requested = true,
+                        Key::Char(' ') => is_in_pause = !is_in_pause,
+                        Key::Left => seek_position_in_ns = Some(current_position_in_ns.saturating_sub(SEEK_STEP_IN_NS)),
+                        Key::Right => seek_position_in_ns = Some(current_position_in_ns + SEEK_STEP_IN_NS),
+                        Key::Down => {
+                            let bar_ticks = tempo_map.get_bar_ticks(current_bar.saturating_sub(1));
+                            seek_position_in_ns = Some(tempo_map.get_time_in_ns(bar_ticks));
+                        }
+                        Key::Up => {
+                            let bar_ticks = tempo_map.get_bar_ticks(current_bar + 1);
+                            seek_position_in_ns = Some(tempo_map.get_time_in_ns(bar_ticks));
+                        }
+                        Key::Home => seek_position_in_ns = Some(0),
+                        Key::Char('+') | Key::Char('-') => {
+                            *speed_percent = match key {
+                                Key::Char('+') => std::cmp::min(*speed_percent + SPEED_STEP_PERCENT, MAX_SPEED_PERCENT),
+                                _ => std::cmp::max(*speed_percent - SPEED_STEP_PERCENT, MIN_SPEED_PERCENT),
+                            };
+                            scheduler.set_speed(*speed_percent);
+                            song_info.set_speed(*speed_percent);
+                            update_screen(ui, &keyboard, &layout, &song_info.lines(), &PLAYER_HELP, Some(&piano_roll));
+                        }
+                        Key::Char('a') | Key::Char('b') | Key::Char('c') => {
+                            match key {
+                                Key::Char('a') => loop_start_in_ns = Some(current_position_in_ns),
+                                Key::Char('b') => loop_end_in_ns = Some(current_position_in_ns),
+                                _ => {
+                                    loop_start_in_ns = None;
+                                    loop_end_in_ns = None;
+                                }
+                            }
+                            song_info.set_loop(loop_start_in_ns, loop_end_in_ns);
+                            update_screen(ui, &keyboard, &layout, &song_info.lines(), &PLAYER_HELP, Some(&piano_roll));
                         }
-                        let (this_x, this_y) = init_ref_pos(w as usize, h as usize);
-                        x = this_x;
-                        y = this_y;
-                    },
-                    Ok(Event::KeyEvent(key)) => {
-                        match key {
-                            Key::Ctrl('q') => exit_requested = true,
-                            Key::Char(' ') => is_in_pause = !is_in_pause,
-                            _ => (),
+                        Key::Char(c) if c.is_digit(10) => {
+                            seek_position_in_ns = Some(song_length_in_ns / 10 * c.to_digit(10).unwrap() as u64)
                         }
-                    },
-                    Err(e) => { println!("Error occured in rustbox: {}", e.description()); return (); },
-                };
+                        Key::Char('[') | Key::Char(']') => {
+                            layout.scroll(key == Key::Char(']'));
+                            update_screen(ui, &keyboard, &layout, &song_info.lines(), &PLAYER_HELP, Some(&piano_roll));
+                        }
+                        _ => (),
+                    }
+                },
+                Err(e) => { println!("Error occured in rustbox: {}", e.description()); return false; },
+            };
+        }
+
+        match seek_position_in_ns.take() {
+            Some(seek_position_in_ns) => {
+                // the events before the new position are not played, but the
+                // keyboard, the song information and the synth are set as if they were
+                let new_position_in_ns = std::cmp::min(seek_position_in_ns, song_length_in_ns);
+                i = song.iter().position(|x| x.time_in_ns >= new_position_in_ns).unwrap_or(nb_events);
+
+                keyboard = KeysColor::new(options.show_pedal_held_keys);
+                song_info = SongInfo::new(title.clone());
+                song_info.set_speed(*speed_percent);
+                song_info.set_loop(loop_start_in_ns, loop_end_in_ns);
+                for event in song[..i].iter() {
+                    update_keyboard(&mut keyboard, &event.key_events, &event.midi_messages);
+                    song_info.update(&event.meta_events);
+                }
 
+                let (state_messages, sounding_notes) = get_synth_state(song, i);
+                synth.seek(&state_messages, sounding_notes, is_in_pause);
+
+                song_info.set_position(tempo_map, new_position_in_ns);
+                piano_roll.set_position(new_position_in_ns);
+                update_screen(ui, &keyboard, &layout, &song_info.lines(), &PLAYER_HELP, Some(&piano_roll));
+                position_in_ns = new_position_in_ns;
+                scheduler.set_position(new_position_in_ns);
+            }
+            None => {
+                let current_event = &song[i];
+                update_keyboard(&mut keyboard, &current_event.key_events, &current_event.midi_messages);
+                song_info.update(&current_event.meta_events);
+                song_info.set_position(tempo_map, current_event.time_in_ns);
+                piano_roll.set_position(current_event.time_in_ns);
+                update_screen(ui, &keyboard, &layout, &song_info.lines(), &PLAYER_HELP, Some(&piano_roll));
+                synth.play(&current_event.midi_messages);
+
+                position_in_ns = current_event.time_in_ns;
+                i += 1;
             }
         }
     }
+
+    true
+}
+
+// plays the songs back-to-back
+pub fn play(songs: Vec<(utils::Song, TempoMap)>, midi_output_port: u32, options: PlayOptions) {
+    let midi_out = midir::MidiOutput::new("Midi output from pianoterm-rs");
+    if let Err(e) = midi_out {
+        println!("Error occured while initialising the midi output: {}", e.description());
+        return ();
+    }
+
+    let midi_out = midi_out.unwrap();
+    let conn_out = midi_out.connect(midi_output_port, "output midi port from pianoterm-rs");
+
+    if let Err(e) = conn_out {
+        println!("Failed to open midi output port: {}", e.kind().description());
+        return ();
+    }
+
+    let mut synth = Synth::new(conn_out.unwrap());
+    let ui = RustBox::init(Default::default());
+    if let Err(e) = ui {
+        println!("Failed to initialise the user interface (rustbox): {}", e.description());
+        return ();
+    };
+
+    let mut ui = ui.unwrap();
+
+    let mut speed_percent = options.speed_percent;
+    let nb_songs = songs.len();
+    for (i, &(ref song, ref tempo_map)) in songs.iter().enumerate() {
+        let title = match nb_songs {
+            1 => get_song_title(song),
+            _ => format!("song {}/{}: {}", i + 1, nb_songs, get_song_title(song)),
+        };
+        if !play_song(&mut ui, &mut synth, song, tempo_map, title, &options, &mut speed_percent) {
+            return;
+        }
+    }
 }
 
-pub fn play_midi_input(midi_input_port: u32, midi_output_port: u32) {
+// plays what is received from the midi input port. If record_filename is given,
+// the received midi messages are saved into that file when the session ends.
+pub fn play_midi_input(midi_input_port: u32,
+                       midi_output_port: u32,
+                       record_filename: Option<&str>,
+                       show_pedal_held_keys: bool) {
 
     let midi_out = midir::MidiOutput::new("Midi output from pianoterm-rs");
     if let Err(e) = midi_out {
@@ -341,7 +1233,7 @@ pub fn play_midi_input(midi_input_port: u32, midi_output_port: u32) {
         return ();
     }
 
-    let mut conn_out = conn_out.unwrap();
+    let mut synth = Synth::new(conn_out.unwrap());
 
 
     let ui = RustBox::init(Default::default());
@@ -350,11 +1242,17 @@ pub fn play_midi_input(midi_input_port: u32, midi_output_port: u32) {
         return ();
     };
 
-    let ui = ui.unwrap();
-    let (mut x, mut y) = init_ref_pos(ui.width(), ui.height());
+    let mut ui = ui.unwrap();
+    let pitch_range = (utils::LA_0, utils::DO_8);
+    let mut layout = KeyboardLayout::new(ui.width(), ui.height(), pitch_range);
+
+    let info = match record_filename {
+        Some(filename) => vec![format!("recording to {}", filename)],
+        None => vec![],
+    };
 
-    let mut keyboard = KeysColor::new();
-    update_screen(&ui, &keyboard, x, y);
+    let mut keyboard = KeysColor::new(show_pedal_held_keys);
+    update_screen(&mut ui, &keyboard, &layout, &info, &LIVE_HELP, None);
 
     let midi_in = midir::MidiInput::new("Midi input from pianoterm-rs");
     if let Err(e) = midi_in {
@@ -366,32 +1264,60 @@ pub fn play_midi_input(midi_input_port: u32, midi_output_port: u32) {
 
 
     let (tx, rx) = std::sync::mpsc::channel();
-    let conn_in = midi_in.connect(midi_input_port, "input midi port from pianoterm-rs", move |_timestamp, message, _| {
-        let key_events = utils::midi_to_music_events(message);
+    let conn_in = midi_in.connect(midi_input_port, "input midi port from pianoterm-rs", move |timestamp, message, elapsed_time_in_ns| {
+        // the timestamp is the number of seconds elapsed since the previous message
+        *elapsed_time_in_ns += (timestamp * 1_000_000_000.0) as u64;
+        let mut key_events = utils::midi_to_music_events(message);
+        key_events.time_in_ns = *elapsed_time_in_ns;
+        for key_event in key_events.key_events.iter_mut() {
+            key_event.time_in_ns = *elapsed_time_in_ns;
+        }
         tx.send(key_events).unwrap();
-    }, ());
+    }, 0u64);
 
     if let Err(e) = conn_in {
         println!("Failed to open midi input port: {}", e.kind().description());
         return ();
     }
 
+    // the recording starts with the first message received, and is done at the
+    // default tempo
+    let mut recorded_events: Vec<MidiEvent> = Vec::new();
+    let mut recording_start_in_ns: Option<u64> = None;
+    let recording_tempo_map = TempoMap::new(TimeDivision::TicksPerQuarterNote(midi_writer::DEFAULT_TICKDIV));
+
     loop {
         if EXIT_REQUESTED_BY_SIGNAL.load(Ordering::Relaxed) {
             EXIT_REQUESTED_BY_SIGNAL.store(false, Ordering::Relaxed);
-            return;
+            break;
         }
 
         match rx.recv_timeout(std::time::Duration::from_millis(100)) {
             Ok(input_music) => {
-                update_keyboard(&mut keyboard, &input_music.key_events);
-                play_music(&mut conn_out, &input_music.midi_messages);
-                update_screen(&ui, &keyboard, x, y);
+                update_keyboard(&mut keyboard, &input_music.key_events, &input_music.midi_messages);
+                synth.play(&input_music.midi_messages);
+                update_screen(&mut ui, &keyboard, &layout, &info, &LIVE_HELP, None);
+
+                if record_filename.is_some() {
+                    let start = *recording_start_in_ns.get_or_insert(input_music.time_in_ns);
+                    for message in input_music.midi_messages {
+                        // only channel messages can be written in a midi file
+                        if (message[0] >= 0x80) && (message[0] < 0xF0) {
+                            let time_in_ns = input_music.time_in_ns - start;
+                            recorded_events.push(MidiEvent {
+                                                     ticks: recording_tempo_map.get_ticks(time_in_ns),
+                                                     time: time_in_ns,
+                                                     track: 0,
+                                                     data: message,
+                                                 });
+                        }
+                    }
+                }
             },
             Err(std::sync::mpsc::RecvTimeoutError::Timeout) => (),
             Err(e) => {
                 println!("Failed to receive input data {}", e.description());
-                return;
+                break;
             },
         };
 
@@ -403,18 +1329,148 @@ pub fn play_midi_input(midi_input_port: u32, midi_output_port: u32) {
                 if (w < 0) || (h < 0) {
                     panic!("new window size has negtive components. Can't happen after a successful init!");
                 }
-                let (this_x, this_y) = init_ref_pos(w as usize, h as usize);
-                x = this_x;
-                y = this_y;
-                update_screen(&ui, &keyboard, x, y);
+                layout = KeyboardLayout::new(w as usize, h as usize, pitch_range);
+                update_screen(&mut ui, &keyboard, &layout, &info, &LIVE_HELP, None);
             },
             Ok(Event::KeyEvent(key)) => {
                 match key {
-                    Key::Ctrl('q') => return,
+                    Key::Ctrl('q') => break,
+                    Key::Char('[') | Key::Char(']') => {
+                        layout.scroll(key == Key::Char(']'));
+                        update_screen(&mut ui, &keyboard, &layout, &info, &LIVE_HELP, None);
+                    }
                     _ => (),
                 }
             },
-            Err(e) => { println!("Error occured in rustbox: {}", e.description()); return (); },
+            Err(e) => { println!("Error occured in rustbox: {}", e.description()); break; },
         };
     }
+
+    if let Some(filename) = record_filename {
+        // leave the user interface first so that errors can be read
+        drop(ui);
+        if let Err(e) = midi_writer::save_midi_song(filename, &recorded_events, &[], &recording_tempo_map, SmfFormat::SingleTrack) {
+            println!("Failed to save the recording: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use canvas::CellGrid;
+
+    const UPPER_ROW: &'static str = " ███│█████│███████│█████│███████│█████│███████│▒░███│███████│█████│███████│█████│███████│█████│███████│█";
+    const LOWER_ROW: &'static str = " █│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│▒│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█│█";
+
+    fn draw_compact_keyboard(keyboard: &KeysColor) -> CellGrid {
+        let mut grid = CellGrid::new(COMPACT_KEYBOARD.width, KEYBOARD_HEIGHT);
+        draw_keyboard(&mut grid, keyboard, &COMPACT_KEYBOARD, 0, 0);
+        grid
+    }
+
+    #[test]
+    fn draws_the_pressed_keys() {
+        // do 4 and do# 4, on the first two channels
+        let mut keyboard = KeysColor::new(false);
+        keyboard.press_key(60, 0, 50);
+        keyboard.press_key(61, 1, 20);
+        let grid = draw_compact_keyboard(&keyboard);
+
+        let expected: Vec<&str> = vec![UPPER_ROW; 5].into_iter().chain(vec![LOWER_ROW; 3]).collect();
+        assert_eq!(grid.to_text(false), expected.join("\n") + "\n");
+
+        // the keys take the colors of their channel, and so does the line
+        // between do and re under the dieses
+        let text = grid.to_text(true);
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].contains("\x1b[0;30;47m│\x1b[0;34m▒\x1b[0;32m░\x1b[0;37m█"));
+        assert!(lines[5].contains("\x1b[0;37m█\x1b[0;30;47m│\x1b[0;34m▒\x1b[0;30;44m│\x1b[0;37m█"));
+
+        keyboard.release_key(60, 0);
+        keyboard.release_key(61, 1);
+        let expected: Vec<String> = expected.iter().map(|x| x.replace("▒", "█").replace("░", "█")).collect();
+        assert_eq!(draw_compact_keyboard(&keyboard).to_text(false), expected.join("\n") + "\n");
+    }
+
+    fn get_song(messages: Vec<Vec<utils::MidiMessage>>) -> utils::Song {
+        messages.into_iter()
+            .enumerate()
+            .map(|(i, midi_messages)| {
+                     utils::MusicEvent {
+                         time_in_ns: i as u64 * 1_000_000,
+                         midi_messages: midi_messages,
+                         key_events: vec![],
+                         meta_events: vec![],
+                     }
+                 })
+            .collect()
+    }
+
+    // the state messages of the first channel, after checking the other ones
+    // are only reset
+    fn get_first_channel_state(song: &utils::Song, index: usize) -> Vec<utils::MidiMessage> {
+        let (state_messages, _) = get_synth_state(song, index);
+        for channel in 1..16 {
+            assert!(state_messages.contains(&vec![0xB0 | channel, RESET_ALL_CONTROLLERS, 0]));
+        }
+        state_messages.into_iter().take_while(|x| x[0] == 0xB0).collect()
+    }
+
+    #[test]
+    fn selects_the_parameters_before_setting_them_again() {
+        // a pitch bend range of 12 semitones, then a note
+        let song = get_song(vec![vec![vec![0xB0, 0x65, 0x00], vec![0xB0, 0x64, 0x00], vec![0xB0, 0x06, 0x0C]],
+                                 vec![vec![0x90, 60, 100]],
+                                 vec![vec![0x80, 60, 0]]]);
+
+        let (_, sounding_notes) = get_synth_state(&song, 2);
+        assert_eq!(sounding_notes[0][60], 100);
+        assert_eq!(get_first_channel_state(&song, 2),
+                   vec![vec![0xB0, RESET_ALL_CONTROLLERS, 0],
+                        vec![0xB0, 0x65, 0x00],
+                        vec![0xB0, 0x64, 0x00],
+                        vec![0xB0, 0x06, 0x0C],
+                        vec![0xB0, 0x65, 0x00],
+                        vec![0xB0, 0x64, 0x00]]);
+    }
+
+    #[test]
+    fn sets_every_parameter_again_in_the_song_order() {
+        // the pitch bend range and the fine tuning, then a non registered
+        // parameter set twice and left selected
+        let song = get_song(vec![vec![vec![0xB0, 0x65, 0x00],
+                                      vec![0xB0, 0x64, 0x00],
+                                      vec![0xB0, 0x06, 0x0C],
+                                      vec![0xB0, 0x26, 0x20],
+                                      vec![0xB0, 0x64, 0x01],
+                                      vec![0xB0, 0x06, 0x40],
+                                      vec![0xB0, 0x65, 0x7F],
+                                      vec![0xB0, 0x64, 0x7F],
+                                      vec![0xB0, 0x07, 0x64]],
+                                 vec![vec![0xB0, 0x63, 0x01], vec![0xB0, 0x62, 0x08], vec![0xB0, 0x06, 0x50]],
+                                 vec![vec![0xB0, 0x06, 0x51], vec![0xB0, 0x60, 0x00]]]);
+
+        assert_eq!(get_first_channel_state(&song, 3),
+                   vec![vec![0xB0, RESET_ALL_CONTROLLERS, 0],
+                        vec![0xB0, 0x07, 0x64],
+                        vec![0xB0, 0x65, 0x00],
+                        vec![0xB0, 0x64, 0x00],
+                        vec![0xB0, 0x06, 0x0C],
+                        vec![0xB0, 0x26, 0x20],
+                        vec![0xB0, 0x65, 0x00],
+                        vec![0xB0, 0x64, 0x01],
+                        vec![0xB0, 0x06, 0x40],
+                        vec![0xB0, 0x63, 0x01],
+                        vec![0xB0, 0x62, 0x08],
+                        vec![0xB0, 0x06, 0x51],
+                        vec![0xB0, 0x63, 0x01],
+                        vec![0xB0, 0x62, 0x08]]);
+
+        // nothing is left selected after a reset
+        let song = get_song(vec![vec![vec![0xB0, 0x65, 0x00], vec![0xB0, 0x64, 0x00], vec![0xB0, 0x06, 0x0C]],
+                                 vec![vec![0xB0, RESET_ALL_CONTROLLERS, 0]]]);
+        assert_eq!(&get_first_channel_state(&song, 2)[4..],
+                   &[vec![0xB0, 0x65, 0x7F], vec![0xB0, 0x64, 0x7F]]);
+    }
 }
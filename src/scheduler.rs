@@ -0,0 +1,239 @@
+use std;
+
+// the source of time of the scheduler, in nanoseconds since an arbitrary origin
+pub trait Clock {
+    fn now_in_ns(&self) -> u64;
+}
+
+pub struct SystemClock {
+    origin: std::time::Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock { origin: std::time::Instant::now() }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_in_ns(&self) -> u64 {
+        let elapsed = self.origin.elapsed();
+        elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64
+    }
+}
+
+// tells when a song position is reached. The position advances at the playback
+// speed from an anchor (a song position and the clock time it was at), except
+// in pause. The anchor only moves when the speed, the pause or the position
+// change, so the deadlines are absolute: being late on an event (e.g. while
+// drawing the screen) does not delay the following ones.
+pub struct Scheduler<C: Clock> {
+    clock: C,
+    anchor_position_in_ns: u64,
+    anchor_time_in_ns: u64,
+    speed_percent: u32,
+    is_in_pause: bool,
+}
+
+impl<C: Clock> Scheduler<C> {
+    // the song starts now, at position 0
+    pub fn new(clock: C, speed_percent: u32) -> Self {
+        let now_in_ns = clock.now_in_ns();
+        Scheduler {
+            clock: clock,
+            anchor_position_in_ns: 0,
+            anchor_time_in_ns: now_in_ns,
+            speed_percent: std::cmp::max(speed_percent, 1),
+            is_in_pause: false,
+        }
+    }
+
+    fn get_position_at(&self, time_in_ns: u64) -> u64 {
+        if self.is_in_pause {
+            self.anchor_position_in_ns
+        } else {
+            let elapsed_time_in_ns = time_in_ns - self.anchor_time_in_ns;
+            self.anchor_position_in_ns + elapsed_time_in_ns * self.speed_percent as u64 / 100
+        }
+    }
+
+    pub fn get_position_in_ns(&self) -> u64 {
+        self.get_position_at(self.clock.now_in_ns())
+    }
+
+    // the position is kept where it is now
+    fn move_anchor(&mut self) {
+        let now_in_ns = self.clock.now_in_ns();
+        self.anchor_position_in_ns = self.get_position_at(now_in_ns);
+        self.anchor_time_in_ns = now_in_ns;
+    }
+
+    pub fn set_position(&mut self, position_in_ns: u64) {
+        self.anchor_position_in_ns = position_in_ns;
+        self.anchor_time_in_ns = self.clock.now_in_ns();
+    }
+
+    pub fn set_speed(&mut self, speed_percent: u32) {
+        self.move_anchor();
+        self.speed_percent = std::cmp::max(speed_percent, 1);
+    }
+
+    pub fn set_pause(&mut self, is_in_pause: bool) {
+        self.move_anchor();
+        self.is_in_pause = is_in_pause;
+    }
+
+    // returns the clock time left until position_in_ns is reached, 0 if it
+    // already is, or None if it can not be reached because of the pause
+    pub fn get_time_to_wait_in_ns(&self, position_in_ns: u64) -> Option<u64> {
+        let now_in_ns = self.clock.now_in_ns();
+        if self.get_position_at(now_in_ns) >= position_in_ns {
+            return Some(0);
+        }
+
+        if self.is_in_pause {
+            return None;
+        }
+
+        // rounded up, so that the position is reached at the deadline
+        let speed = self.speed_percent as u64;
+        let deadline_in_ns = self.anchor_time_in_ns + ((position_in_ns - self.anchor_position_in_ns) * 100 + speed - 1) / speed;
+        Some(deadline_in_ns.saturating_sub(now_in_ns))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // a clock only moving when the test steps it
+    #[derive(Clone)]
+    struct ManualClock {
+        now_in_ns: Rc<Cell<u64>>,
+    }
+
+    impl ManualClock {
+        fn new(now_in_ns: u64) -> Self {
+            ManualClock { now_in_ns: Rc::new(Cell::new(now_in_ns)) }
+        }
+
+        fn advance(&self, duration_in_ns: u64) {
+            self.now_in_ns.set(self.now_in_ns.get() + duration_in_ns);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now_in_ns(&self) -> u64 {
+            self.now_in_ns.get()
+        }
+    }
+
+    // an event every 1.000003 ms, so that the deadlines are rounded at speeds
+    // other than 100%
+    fn get_event_position(index: u64) -> u64 {
+        index * 1_000_003
+    }
+
+    // waits for the events like the player does, each one taking
+    // processing_time_in_ns to handle. The deadline of each event must be the
+    // one computed from the anchor, however many events were played since.
+    fn play_events(scheduler: &Scheduler<ManualClock>,
+                   clock: &ManualClock,
+                   events: std::ops::Range<u64>,
+                   processing_time_in_ns: u64) {
+        let anchor_time_in_ns = clock.now_in_ns();
+        let anchor_position_in_ns = scheduler.get_position_in_ns();
+        let speed = scheduler.speed_percent as u64;
+
+        for index in events {
+            let position_in_ns = get_event_position(index);
+            let time_to_wait_in_ns = scheduler.get_time_to_wait_in_ns(position_in_ns).unwrap();
+            clock.advance(time_to_wait_in_ns);
+
+            let expected_time_in_ns = anchor_time_in_ns + ((position_in_ns - anchor_position_in_ns) * 100 + speed - 1) / speed;
+            assert_eq!(clock.now_in_ns(), expected_time_in_ns, "event {}", index);
+            assert!(scheduler.get_position_in_ns() >= position_in_ns);
+            assert_eq!(scheduler.get_time_to_wait_in_ns(position_in_ns), Some(0));
+
+            clock.advance(processing_time_in_ns);
+        }
+    }
+
+    #[test]
+    fn deadlines_do_not_drift() {
+        let clock = ManualClock::new(1_000);
+        let mut scheduler = Scheduler::new(clock.clone(), 100);
+
+        play_events(&scheduler, &clock, 0..2_000, 1_234);
+        assert_eq!(clock.now_in_ns(), 1_000 + get_event_position(1_999) + 1_234);
+
+        scheduler.set_speed(150);
+        play_events(&scheduler, &clock, 2_000..4_000, 1_234);
+
+        scheduler.set_speed(37);
+        play_events(&scheduler, &clock, 4_000..6_000, 1_234);
+
+        // the position does not move in pause, whatever the clock does
+        scheduler.set_pause(true);
+        let position_in_ns = scheduler.get_position_in_ns();
+        clock.advance(5_000_000_000);
+        assert_eq!(scheduler.get_position_in_ns(), position_in_ns);
+        assert_eq!(scheduler.get_time_to_wait_in_ns(get_event_position(6_000)), None);
+        assert_eq!(scheduler.get_time_to_wait_in_ns(position_in_ns), Some(0));
+
+        // nor when the speed changes in pause
+        scheduler.set_speed(250);
+        clock.advance(5_000_000_000);
+        assert_eq!(scheduler.get_position_in_ns(), position_in_ns);
+
+        scheduler.set_pause(false);
+        assert_eq!(scheduler.get_position_in_ns(), position_in_ns);
+        play_events(&scheduler, &clock, 6_000..8_000, 1_234);
+
+        // going back in the song
+        scheduler.set_position(get_event_position(100));
+        assert_eq!(scheduler.get_position_in_ns(), get_event_position(100));
+        play_events(&scheduler, &clock, 100..2_100, 1_234);
+
+        scheduler.set_position(get_event_position(9_000) - 1);
+        play_events(&scheduler, &clock, 9_000..11_000, 1_234);
+    }
+
+    #[test]
+    fn late_events_do_not_delay_the_next_ones() {
+        let clock = ManualClock::new(0);
+        let scheduler = Scheduler::new(clock.clone(), 100);
+
+        play_events(&scheduler, &clock, 0..10, 0);
+
+        // handling the event 10 takes more than 3 events long: the next 3
+        // ones are already due when it is done
+        clock.advance(get_event_position(10) - clock.now_in_ns());
+        assert_eq!(scheduler.get_time_to_wait_in_ns(get_event_position(10)), Some(0));
+        clock.advance(get_event_position(3) + 500_000);
+        for index in 11..14 {
+            assert_eq!(scheduler.get_time_to_wait_in_ns(get_event_position(index)), Some(0));
+        }
+
+        // the event 14 keeps its deadline, only 500 us are left to wait
+        assert_eq!(scheduler.get_time_to_wait_in_ns(get_event_position(14)), Some(500_003));
+        clock.advance(500_003);
+        assert_eq!(clock.now_in_ns(), get_event_position(14));
+
+        // and so do the following events, even when each one takes longer to
+        // handle than the time between two events
+        play_events(&scheduler, &clock, 15..1_000, 999_000);
+        for index in 1_000..1_010 {
+            if let Some(time_to_wait_in_ns) = scheduler.get_time_to_wait_in_ns(get_event_position(index)) {
+                clock.advance(time_to_wait_in_ns);
+            }
+            clock.advance(2_000_000);
+        }
+        assert_eq!(clock.now_in_ns(), get_event_position(1_000) + 10 * 2_000_000);
+        assert_eq!(scheduler.get_time_to_wait_in_ns(get_event_position(1_019)), Some(0));
+        assert_eq!(scheduler.get_time_to_wait_in_ns(get_event_position(1_021)),
+                   Some(get_event_position(1_021) - clock.now_in_ns()));
+    }
+}
@@ -0,0 +1,269 @@
+use std;
+
+// how midi ticks relate to time, as given in the header of a midi file
+#[derive(Clone, Copy)]
+pub enum TimeDivision {
+    TicksPerQuarterNote(u16),
+    // drop_frame stands for the 29.97 frames per second rate. Its ticks are
+    // 1001/1000 times longer than the nominal ones. ticks_per_frame is the
+    // resolution within a frame given in the header.
+    Timecode { ticks_per_second: u16, ticks_per_frame: u8, drop_frame: bool },
+}
+
+#[derive(Clone, Copy)]
+struct TempoChange {
+    ticks: u64,
+    time_in_ns: u64,
+    ns_per_quarter_note: u64,
+}
+
+#[derive(Clone, Copy)]
+struct TimeSignatureChange {
+    ticks: u64,
+    bar: u64, // index of the bar starting at ticks
+    numerator: u64,
+    denominator: u64,
+}
+
+// converts midi ticks into nanoseconds and back, and into bars and beats,
+// according to the tempo and time signature changes of a song.
+#[derive(Clone)]
+pub struct TempoMap {
+    division: TimeDivision,
+    tempo_changes: Vec<TempoChange>,
+    time_signatures: Vec<TimeSignatureChange>,
+}
+
+// computes value * numerator / denominator without overflowing on the
+// intermediate product, rounded down or to the nearest integer.
+fn scale(value: u64, numerator: u64, denominator: u64, round_to_nearest: bool) -> u64 {
+    let rounding = if round_to_nearest { denominator / 2 } else { 0 };
+
+    (value / denominator) * numerator + ((value % denominator) * numerator + rounding) / denominator
+}
+
+// index of the last element whose key is lower or equal to value. elements are
+// sorted by their keys, which are unique, and the first key is 0.
+fn find_last_before<T, F>(elements: &[T], value: u64, get_key: F) -> usize
+    where F: Fn(&T) -> u64
+{
+    match elements.binary_search_by_key(&value, get_key) {
+        Ok(pos) => pos,
+        Err(pos) => pos - 1,
+    }
+}
+
+impl TempoMap {
+    // the default tempo is 120 beats per minute, and the default time
+    // signature is 4/4
+    pub fn new(division: TimeDivision) -> Self {
+        TempoMap {
+            division: division,
+            tempo_changes: vec![TempoChange {
+                                    ticks: 0,
+                                    time_in_ns: 0,
+                                    ns_per_quarter_note: 500_000_000,
+                                }],
+            time_signatures: vec![TimeSignatureChange {
+                                      ticks: 0,
+                                      bar: 0,
+                                      numerator: 4,
+                                      denominator: 4,
+                                  }],
+        }
+    }
+
+    // changes must be added in the order of their ticks. Tempo changes have no
+    // effect on the timings of timecode based songs.
+    pub fn add_tempo_change(&mut self, ticks: u64, us_per_quarter_note: u32) {
+        let time_in_ns = self.get_time_in_ns(ticks);
+        let change = TempoChange {
+            ticks: ticks,
+            time_in_ns: time_in_ns,
+            ns_per_quarter_note: std::cmp::max(us_per_quarter_note, 1) as u64 * 1_000,
+        };
+
+        if self.tempo_changes.last().unwrap().ticks == ticks {
+            let _ = self.tempo_changes.pop();
+        }
+        self.tempo_changes.push(change);
+    }
+
+    // changes must be added in the order of their ticks. A time signature
+    // change happening in the middle of a bar starts a new bar.
+    pub fn add_time_signature(&mut self, ticks: u64, numerator: u8, denominator: u16) {
+        let bar = {
+            let last = self.time_signatures.last().unwrap();
+            let bar_length = self.get_bar_length(last);
+            last.bar + (ticks - last.ticks + bar_length - 1) / bar_length
+        };
+
+        let change = TimeSignatureChange {
+            ticks: ticks,
+            bar: bar,
+            numerator: std::cmp::max(numerator, 1) as u64,
+            denominator: std::cmp::max(denominator, 1) as u64,
+        };
+
+        if self.time_signatures.last().unwrap().ticks == ticks {
+            let _ = self.time_signatures.pop();
+        }
+        self.time_signatures.push(change);
+    }
+
+    pub fn get_division(&self) -> TimeDivision {
+        self.division
+    }
+
+    pub fn get_time_in_ns(&self, ticks: u64) -> u64 {
+        match self.division {
+            TimeDivision::Timecode { ticks_per_second, drop_frame, .. } => {
+                let time_in_ns = scale(ticks, 1_000_000_000, ticks_per_second as u64, false);
+                if drop_frame {
+                    scale(time_in_ns, 1_001, 1_000, false)
+                } else {
+                    time_in_ns
+                }
+            }
+            TimeDivision::TicksPerQuarterNote(tickdiv) => {
+                let pos = find_last_before(&self.tempo_changes, ticks, |x| x.ticks);
+                let change = &self.tempo_changes[pos];
+                change.time_in_ns + scale(ticks - change.ticks, change.ns_per_quarter_note, tickdiv as u64, false)
+            }
+        }
+    }
+
+    // rounded to the nearest tick
+    pub fn get_ticks(&self, time_in_ns: u64) -> u64 {
+        match self.division {
+            TimeDivision::Timecode { ticks_per_second, drop_frame, .. } => {
+                let time_in_ns = if drop_frame { scale(time_in_ns, 1_000, 1_001, true) } else { time_in_ns };
+                scale(time_in_ns, ticks_per_second as u64, 1_000_000_000, true)
+            }
+            TimeDivision::TicksPerQuarterNote(tickdiv) => {
+                let pos = find_last_before(&self.tempo_changes, time_in_ns, |x| x.time_in_ns);
+                let change = &self.tempo_changes[pos];
+                change.ticks + scale(time_in_ns - change.time_in_ns, tickdiv as u64, change.ns_per_quarter_note, true)
+            }
+        }
+    }
+
+    // timecode based songs have no notion of quarter notes. They are considered
+    // to be played at the default tempo.
+    fn get_quarter_note_length(&self) -> u64 {
+        match self.division {
+            TimeDivision::TicksPerQuarterNote(tickdiv) => tickdiv as u64,
+            TimeDivision::Timecode { ticks_per_second, .. } => ticks_per_second as u64 / 2,
+        }
+    }
+
+    fn get_beat_length(&self, time_signature: &TimeSignatureChange) -> u64 {
+        std::cmp::max(self.get_quarter_note_length() * 4 / time_signature.denominator, 1)
+    }
+
+    fn get_bar_length(&self, time_signature: &TimeSignatureChange) -> u64 {
+        self.get_beat_length(time_signature) * time_signature.numerator
+    }
+
+    // returns the index of the bar, and of the beat within that bar, at the
+    // given ticks. Both start at 0.
+    pub fn get_bar_and_beat(&self, ticks: u64) -> (u64, u64) {
+        let pos = find_last_before(&self.time_signatures, ticks, |x| x.ticks);
+        let time_signature = &self.time_signatures[pos];
+        let bar_length = self.get_bar_length(time_signature);
+        let ticks_since_change = ticks - time_signature.ticks;
+        let bar = time_signature.bar + ticks_since_change / bar_length;
+        let beat = (ticks_since_change % bar_length) / self.get_beat_length(time_signature);
+        (bar, beat)
+    }
+
+    // returns the ticks at which the bar starts. Bars start at 0.
+    pub fn get_bar_ticks(&self, bar: u64) -> u64 {
+        let pos = find_last_before(&self.time_signatures, bar, |x| x.bar);
+        let time_signature = &self.time_signatures[pos];
+        time_signature.ticks + (bar - time_signature.bar) * self.get_bar_length(time_signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_ticks_across_a_tempo_change() {
+        // 120 then 240 beats per minute from the third beat
+        let mut tempo_map = TempoMap::new(TimeDivision::TicksPerQuarterNote(480));
+        tempo_map.add_tempo_change(960, 250_000);
+
+        assert_eq!(tempo_map.get_time_in_ns(480), 500_000_000);
+        assert_eq!(tempo_map.get_time_in_ns(960), 1_000_000_000);
+        assert_eq!(tempo_map.get_time_in_ns(1440), 1_250_000_000);
+        assert_eq!(tempo_map.get_ticks(1_250_000_000), 1440);
+
+        for ticks in (0..5_000).filter(|x| x % 7 == 0) {
+            assert_eq!(tempo_map.get_ticks(tempo_map.get_time_in_ns(ticks)), ticks);
+        }
+
+        // rounded to the nearest tick, a tick lasting 520833 ns after the change
+        assert_eq!(tempo_map.get_ticks(1_000_000_000 + 260_000), 960);
+        assert_eq!(tempo_map.get_ticks(1_000_000_000 + 261_000), 961);
+    }
+
+    #[test]
+    fn counts_bars_and_beats_across_time_signature_changes() {
+        // a 3/4 bar lasts as long as a 6/8 one, with half as many beats
+        let mut tempo_map = TempoMap::new(TimeDivision::TicksPerQuarterNote(480));
+        tempo_map.add_time_signature(0, 3, 4);
+        tempo_map.add_time_signature(2880, 6, 8);
+
+        assert_eq!(tempo_map.get_bar_and_beat(0), (0, 0));
+        assert_eq!(tempo_map.get_bar_and_beat(1440 + 480), (1, 1));
+        assert_eq!(tempo_map.get_bar_and_beat(2879), (1, 2));
+        assert_eq!(tempo_map.get_bar_and_beat(2880), (2, 0));
+        assert_eq!(tempo_map.get_bar_and_beat(2880 + 5 * 240), (2, 5));
+        assert_eq!(tempo_map.get_bar_and_beat(2880 + 1440), (3, 0));
+        assert_eq!(tempo_map.get_bar_ticks(3), 4320);
+
+        // a change in the middle of a bar starts a new one
+        let mut tempo_map = TempoMap::new(TimeDivision::TicksPerQuarterNote(480));
+        tempo_map.add_time_signature(0, 3, 4);
+        tempo_map.add_time_signature(3600, 6, 8);
+
+        assert_eq!(tempo_map.get_bar_and_beat(3599), (2, 1));
+        assert_eq!(tempo_map.get_bar_and_beat(3600), (3, 0));
+        assert_eq!(tempo_map.get_bar_ticks(2), 2880);
+        assert_eq!(tempo_map.get_bar_ticks(3), 3600);
+        assert_eq!(tempo_map.get_bar_ticks(4), 5040);
+    }
+
+    #[test]
+    fn converts_timecode_ticks_whatever_the_tempo() {
+        // 25 frames per second, 40 ticks per frame
+        let mut tempo_map = TempoMap::new(TimeDivision::Timecode {
+                                              ticks_per_second: 1000,
+                                              ticks_per_frame: 40,
+                                              drop_frame: false,
+                                          });
+        tempo_map.add_tempo_change(500, 250_000);
+
+        assert_eq!(tempo_map.get_time_in_ns(1000), 1_000_000_000);
+        assert_eq!(tempo_map.get_time_in_ns(1), 1_000_000);
+        assert_eq!(tempo_map.get_ticks(1_000_000_000), 1000);
+        assert_eq!(tempo_map.get_ticks(1_499_999), 1);
+
+        // a quarter note lasts half a second, as with the default tempo
+        assert_eq!(tempo_map.get_bar_and_beat(2500), (1, 1));
+
+        // 29.97 frames per second, 4 ticks per frame
+        let tempo_map = TempoMap::new(TimeDivision::Timecode {
+                                          ticks_per_second: 120,
+                                          ticks_per_frame: 4,
+                                          drop_frame: true,
+                                      });
+        assert_eq!(tempo_map.get_time_in_ns(120), 1_001_000_000);
+        assert_eq!(tempo_map.get_ticks(1_001_000_000), 120);
+        for ticks in 0..1_000 {
+            assert_eq!(tempo_map.get_ticks(tempo_map.get_time_in_ns(ticks)), ticks);
+        }
+    }
+}
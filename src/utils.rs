@@ -1,6 +1,7 @@
 use std;
+use std::collections::{BTreeMap, HashSet};
 use keyboard_events_extractor::{KeyEvent, KeyData};
-use midi_reader::MidiEvent;
+use midi_reader::{MidiEvent, MidiMetaEvent, MetaEvent};
 
 pub const LA_0: u8 = 21;
 pub const LA_DIESE_0: u8 = 22;
@@ -13,7 +14,8 @@ pub type MidiMessage = Vec<u8>;
 pub struct MusicEvent {
     pub time_in_ns: u64,
     pub midi_messages: Vec<MidiMessage>,
-    pub key_events: Vec<KeyData>,
+    pub key_events: Vec<KeyEvent>,
+    pub meta_events: Vec<MetaEvent>,
 }
 
 pub type Song = Vec<MusicEvent>;
@@ -21,110 +23,91 @@ pub type Song = Vec<MusicEvent>;
 
 fn is_key_release_event(data: &[u8]) -> bool {
     (data.len() == 3) &&
-    (((data[0] & 0xF0) == 0x80) || (((data[0] & 0xF0) == 0x90) && (data[2] == 0x90)))
+    (((data[0] & 0xF0) == 0x80) || (((data[0] & 0xF0) == 0x90) && (data[2] == 0x00)))
 }
 
 fn is_key_down_event(data: &[u8]) -> bool {
     (data.len() == 3) && ((data[0] & 0xF0) == 0x90) && (data[2] != 0x00)
 }
 
-fn get_variable_data_length(message_stream: &[u8]) -> usize {
-    let max_res = message_stream.len();
-
-    let mut array_size : usize = 0;
-    let mut nb_read = 0;
-
-    for c in message_stream.iter() {
-        nb_read += 1;
-        array_size = (array_size << 7) + (c & 0x7F) as usize;
-        if (c & 0x80) == 0  {
-            return std::cmp::min(max_res, array_size + nb_read);
-        }
-    }
-
-    // at this point input is invalid
-    return max_res;
-}
-
-
-fn get_next_event_size(message_stream: &[u8]) -> usize {
-    let len = message_stream.len();
-
-    if len < 2 {
-        // minimal possible length (midi channel event 0xC0 and 0xD0
-        return len;
-    }
-
-    let mut res = 1;  // the first byte which the event type (channel, meta, sysex)
-    let ev_type = message_stream[0];
-    if ev_type == 0xFF {
-        // META event has one byte more than sysex
-        res += 1;
-    }
-
-    if (ev_type == 0xFF) || (ev_type == 0xF0) || (ev_type == 0xF7) {
-        // end of META or sysex
-        res += get_variable_data_length(&message_stream[res..]);
-
-        // sanity check: in case of wrong input, simply discard data
-        return std::cmp::min(res, len);
-    }
-
-    if ((ev_type & 0xF0) >= 0x80) && ((ev_type & 0xF0) != 0xF0) {
-        if ((ev_type & 0xF0) == 0xC0)
-	    || ((ev_type & 0xF0) == 0xD0)  /* Program Change Event */
-        { /* or Channel Aftertouch Event */
-             // one more byte
-             res += 1;
-        } else {
-            // this is a MIDI channel event (more two bytes)
-            res += 2;
+// returns the size of the midi message starting the stream, as sent on a midi
+// port, i.e. always starting with its status byte. Stray data bytes are
+// returned as one byte long messages.
+fn get_next_message_size(message_stream: &[u8]) -> usize {
+    match message_stream[0] {
+        0x80..=0xBF | 0xE0..=0xEF => 3, // note off/on, key pressure, control change, pitch bend
+        0xC0..=0xDF => 2, // program change, channel pressure
+        0xF0 => {
+            // system exclusive: up to the end of exclusive byte
+            match message_stream.iter().position(|&x| x == 0xF7) {
+                Some(pos) => pos + 1,
+                None => message_stream.len(),
+            }
         }
-
-        return std::cmp::min(res, len);
+        0xF1 | 0xF3 => 2, // time code quarter frame, song select
+        0xF2 => 3, // song position pointer
+        _ => 1, // tune request, end of exclusive, real time messages and data bytes
     }
-
-    // at this point there is an error, discard data
-    return len;
 }
 
+// every message received is kept to be forwarded to the synthesizer. Key
+// events are only made out of the note on and note off messages. Data bytes
+// following a channel message are the data of another message with the same
+// status byte (running status), which is forwarded with its status byte.
 pub fn midi_to_music_events(message_stream: &[u8]) -> MusicEvent {
 
     let mut res = MusicEvent{ midi_messages: Vec::<MidiMessage>::new(),
                               time_in_ns: 0,
-                              key_events: Vec::<KeyData>::new() };
+                              key_events: Vec::<KeyEvent>::new(),
+                              meta_events: Vec::<MetaEvent>::new() };
 
     let size = message_stream.len();
     let mut nb_read = 0;
+    let mut running_status: Option<u8> = None;
 
     while nb_read < size {
-        let this_event_size = get_next_event_size(&message_stream[nb_read .. ]);
-        if this_event_size == 0 {
-            // this is an error. return what we have found so far to avoid
-            // an infinite loop. This can happen with variable length array.
-            // If the computation of the size overflow and falls to 0, then
-            // ...
-            return res;
+        let mut message: Vec<u8> = match running_status {
+            Some(status) if (message_stream[nb_read] & 0x80) == 0 => vec![status],
+            _ => vec![],
+        };
+        let this_message_size = get_next_message_size(&[&message[..], &message_stream[nb_read .. ]].concat());
+        let nb_bytes = this_message_size - message.len();
+        message.extend_from_slice(&message_stream[nb_read .. std::cmp::min(nb_read + nb_bytes, size)]);
+        nb_read += nb_bytes;
+
+        // truncated messages and data bytes without a status byte are discarded
+        if (message.len() != this_message_size) || ((message[0] & 0x80) == 0) {
+            continue;
         }
 
-        if this_event_size == 3 { // can it be a midi key press or key release event?
-            let tmp = &message_stream[nb_read .. nb_read + 3];
-
-            let pitch = tmp[1];
-            if (pitch >= LA_0) && (pitch <= DO_8) {
+        match message[0] {
+            0x80..=0xEF => running_status = Some(message[0]),
+            0xF0..=0xF7 => running_status = None, // system exclusive and system common messages
+            _ => (), // real time messages don't cancel the running status
+        }
 
-                if is_key_release_event(&tmp) {
-	            res.key_events.push(KeyData::Released(pitch));
-                }
-                else if is_key_down_event(&tmp) {
-	            res.key_events.push(KeyData::Pressed(pitch));
-                }
+        let key_data = if is_key_release_event(&message) {
+            Some(KeyData::Released(message[1]))
+        } else if is_key_down_event(&message) {
+            Some(KeyData::Pressed(message[1], message[2]))
+        } else {
+            None
+        };
 
-                res.midi_messages.push(tmp.to_vec());
+        // keys out of the keyboard are still heard
+        if let Some(data) = key_data {
+            let pitch = message[1];
+            if (pitch >= LA_0) && (pitch <= DO_8) {
+                res.key_events.push(KeyEvent {
+                                        data: data,
+                                        channel: message[0] & 0x0F,
+                                        track: 0,
+                                        time_in_ns: 0,
+                                    });
             }
         }
 
-        nb_read += this_event_size;
+        res.midi_messages.push(message);
     }
 
     return res;
@@ -143,9 +126,10 @@ fn fix_midi_order(song: &mut Song) {
             let midi_ev = &message;
             if is_key_release_event(midi_ev) {
                 let pitch = midi_ev[1];
+                let channel = midi_ev[0] & 0x0F;
                 match music_event.midi_messages[cur_pos + 1..]
                           .iter()
-                          .position(|&ref x| is_key_down_event(&x) && (x[1] == pitch)) {
+                          .position(|&ref x| is_key_down_event(&x) && (x[1] == pitch) && ((x[0] & 0x0F) == channel)) {
                     Some(down_dist) => pos_to_switch.push((cur_pos, cur_pos + down_dist)),
                     _ => (),
                 }
@@ -160,63 +144,53 @@ fn fix_midi_order(song: &mut Song) {
 
 
 pub fn group_events_by_time(midi_events: &Vec<MidiEvent>,
-                            keyboard_events: &Vec<KeyEvent>)
+                            keyboard_events: &Vec<KeyEvent>,
+                            meta_events: &Vec<MidiMetaEvent>)
                             -> Result<Song, String> {
-    let mut res: Song = Vec::new();
+    // events occuring at the same time are grouped together, in the order they
+    // are given
+    let mut groups: BTreeMap<u64, MusicEvent> = BTreeMap::new();
+    let new_group = |time: u64| {
+        MusicEvent {
+            time_in_ns: time,
+            midi_messages: vec![],
+            key_events: vec![],
+            meta_events: vec![],
+        }
+    };
 
-    // totally suboptimal implementation
     for elt in midi_events {
-        let time = elt.time;
-        match res.iter().position(|ref x| x.time_in_ns == time) {
-            None => {
-                res.push(MusicEvent {
-                             time_in_ns: time,
-                             midi_messages: vec![elt.data.clone()],
-                             key_events: vec![],
-                         })
-            }
-            Some(pos) => res[pos].midi_messages.push(elt.data.clone()),
-        }
+        groups.entry(elt.time).or_insert_with(|| new_group(elt.time)).midi_messages.push(elt.data.clone());
     }
 
     for k in keyboard_events {
-        let ev_time = k.time_in_ns;
-        match res.iter().position(|ref x| x.time_in_ns == ev_time) {
-            None => {
-                res.push(MusicEvent {
-                             time_in_ns: ev_time,
-                             midi_messages: vec![],
-                             key_events: vec![k.data],
-                         })
-            }
-            Some(pos) => res[pos].key_events.push(k.data),
-        }
+        groups.entry(k.time_in_ns).or_insert_with(|| new_group(k.time_in_ns)).key_events.push(*k);
     }
 
-    res.sort_by(|a, b| match (a, b) {
-                    (a, b) if a.time_in_ns < b.time_in_ns => std::cmp::Ordering::Less,
-                    (a, b) if a.time_in_ns == b.time_in_ns => std::cmp::Ordering::Equal,
-                    (a, b) if a.time_in_ns > b.time_in_ns => std::cmp::Ordering::Greater,
-                    _ => panic!("comparison is not total!"),
-                });
+    for m in meta_events {
+        groups.entry(m.time).or_insert_with(|| new_group(m.time)).meta_events.push(m.event.clone());
+    }
+
+    // the groups are sorted by time
+    let mut res: Song = groups.into_iter().map(|(_, group)| group).collect();
 
     // sanity check: all elements in res must hold at least one event
     if let Some(_) = res.iter()
-           .find(|x| x.midi_messages.is_empty() && x.key_events.is_empty()) {
+           .find(|x| x.midi_messages.is_empty() && x.key_events.is_empty() && x.meta_events.is_empty()) {
         return Err("Error: a music event does not contain any midi or key event".to_owned());
     }
 
-    // sanity check: worst case res has as many elts as midi_events + key_events
+    // sanity check: worst case res has as many elts as midi_events + key_events + meta_events
     // (each event occuring at a different time)
-    let nb_input_events = midi_events.len() + keyboard_events.len();
+    let nb_input_events = midi_events.len() + keyboard_events.len() + meta_events.len();
     if res.len() > nb_input_events {
         return Err("Error while grouping events by time, some events just got automagically created".to_owned());
     }
 
-    // sanity check: count the total number of midi and key events in res. It must
+    // sanity check: count the total number of midi, key and meta events in res. It must
     // match the number of parameters given in the parameters
     let nb_events = res.iter()
-        .map(|x| x.midi_messages.len() + x.key_events.len())
+        .map(|x| x.midi_messages.len() + x.key_events.len() + x.meta_events.len())
         .sum::<usize>();
 
     if nb_events > nb_input_events {
@@ -236,22 +210,22 @@ pub fn group_events_by_time(midi_events: &Vec<MidiEvent>,
     }
 
     // sanity check: there must be as many release events as pressed events
-    let count_key_released_events = |key_events: &Vec<KeyData>| {
+    let count_key_released_events = |key_events: &Vec<KeyEvent>| {
         key_events
             .iter()
-            .filter(|&elt| match *elt {
+            .filter(|&elt| match elt.data {
                         KeyData::Released(_) => true,
-                        KeyData::Pressed(_) => false,
+                        KeyData::Pressed(_, _) => false,
                     })
             .count()
     };
 
-    let count_key_pressed_events = |key_events: &Vec<KeyData>| {
+    let count_key_pressed_events = |key_events: &Vec<KeyEvent>| {
         key_events
             .iter()
-            .filter(|&elt| match *elt {
+            .filter(|&elt| match elt.data {
                         KeyData::Released(_) => false,
-                        KeyData::Pressed(_) => true,
+                        KeyData::Pressed(_, _) => true,
                     })
             .count()
     };
@@ -268,25 +242,156 @@ pub fn group_events_by_time(midi_events: &Vec<MidiEvent>,
     }
 
     // sanity check: a key release and a key pressed event with the same pitch
-    // can't appear at the same time
+    // and channel can't appear at the same time
     for elt in res.iter() {
+        let pressed_keys: HashSet<(u8, u8)> = elt.key_events
+            .iter()
+            .filter_map(|x| match x.data {
+                            KeyData::Pressed(pitch, _) => Some((x.channel, pitch)),
+                            KeyData::Released(_) => None,
+                        })
+            .collect();
+
         for k in elt.key_events.iter() {
-            if let KeyData::Released(pitch) = *k {
-                if elt.key_events
-                       .iter()
-                       .find(|&x| match *x {
-                                 KeyData::Pressed(p) if p == pitch => true,
-                                 _ => false,
-                             })
-                       .is_some() {
+            if let KeyData::Released(pitch) = k.data {
+                if pressed_keys.contains(&(k.channel, pitch)) {
                     return Err("Error: a key press happens at the same time as a key release".to_owned());
                 }
             }
         }
-
     }
 
     fix_midi_order(&mut res);
 
     Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyboard_events_extractor;
+    use generated_song::{generate_midi_file, read_generated_song};
+
+    // group_events_by_time as it was before grouping the events in a map, which
+    // looked the group of each event up among all the previous groups. The
+    // sanity checks are left out.
+    fn group_events_by_time_reference(midi_events: &Vec<MidiEvent>,
+                                      keyboard_events: &Vec<KeyEvent>,
+                                      meta_events: &Vec<MidiMetaEvent>)
+                                      -> Song {
+        let mut res: Song = Vec::new();
+        let new_group = |time: u64| {
+            MusicEvent {
+                time_in_ns: time,
+                midi_messages: vec![],
+                key_events: vec![],
+                meta_events: vec![],
+            }
+        };
+
+        for elt in midi_events {
+            match res.iter().position(|ref x| x.time_in_ns == elt.time) {
+                None => {
+                    res.push(new_group(elt.time));
+                    res.last_mut().unwrap().midi_messages.push(elt.data.clone());
+                }
+                Some(pos) => res[pos].midi_messages.push(elt.data.clone()),
+            }
+        }
+
+        for k in keyboard_events {
+            match res.iter().position(|ref x| x.time_in_ns == k.time_in_ns) {
+                None => {
+                    res.push(new_group(k.time_in_ns));
+                    res.last_mut().unwrap().key_events.push(*k);
+                }
+                Some(pos) => res[pos].key_events.push(*k),
+            }
+        }
+
+        for m in meta_events {
+            match res.iter().position(|ref x| x.time_in_ns == m.time) {
+                None => {
+                    res.push(new_group(m.time));
+                    res.last_mut().unwrap().meta_events.push(m.event.clone());
+                }
+                Some(pos) => res[pos].meta_events.push(m.event.clone()),
+            }
+        }
+
+        res.sort_by(|a, b| a.time_in_ns.cmp(&b.time_in_ns));
+        fix_midi_order(&mut res);
+        res
+    }
+
+    fn get_music_event_data(song: &Song) -> Vec<(u64, Vec<MidiMessage>, Vec<(u8, u16, u64, bool, u8)>, Vec<String>)> {
+        song.iter()
+            .map(|x| {
+                let key_events = x.key_events
+                    .iter()
+                    .map(|k| match k.data {
+                             KeyData::Pressed(pitch, _) => (k.channel, k.track, k.time_in_ns, true, pitch),
+                             KeyData::Released(pitch) => (k.channel, k.track, k.time_in_ns, false, pitch),
+                         })
+                    .collect();
+                let meta_events = x.meta_events.iter().map(|m| m.to_string()).collect();
+                (x.time_in_ns, x.midi_messages.clone(), key_events, meta_events)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn groups_the_events_as_the_reference() {
+        for seed in 1..4 {
+            let song = read_generated_song(generate_midi_file(3_000, seed));
+            let key_events = keyboard_events_extractor::get_key_events(&song.events).unwrap();
+
+            let music_events = group_events_by_time(&song.events, &key_events, &song.meta_events).unwrap();
+            let expected = group_events_by_time_reference(&song.events, &key_events, &song.meta_events);
+            assert_eq!(get_music_event_data(&music_events), get_music_event_data(&expected));
+        }
+    }
+
+    #[test]
+    fn classifies_the_midi_messages() {
+        // the input stream, the messages forwarded, and the key events as
+        // (channel, pressed, pitch)
+        let table: Vec<(Vec<u8>, Vec<Vec<u8>>, Vec<(u8, bool, u8)>)> = vec![
+            (vec![0x90, 60, 100], vec![vec![0x90, 60, 100]], vec![(0, true, 60)]),
+            (vec![0x81, 60, 64], vec![vec![0x81, 60, 64]], vec![(1, false, 60)]),
+            // a note on of velocity 0 is a release
+            (vec![0x92, 60, 0], vec![vec![0x92, 60, 0]], vec![(2, false, 60)]),
+            // running status, also across a real time message
+            (vec![0x90, 60, 100, 62, 100, 0xF8, 60, 0],
+             vec![vec![0x90, 60, 100], vec![0x90, 62, 100], vec![0xF8], vec![0x90, 60, 0]],
+             vec![(0, true, 60), (0, true, 62), (0, false, 60)]),
+            (vec![0xC0, 5, 6], vec![vec![0xC0, 5], vec![0xC0, 6]], vec![]),
+            // system exclusive messages are forwarded whole, and cancel the
+            // running status
+            (vec![0x90, 60, 100, 0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7, 62, 100],
+             vec![vec![0x90, 60, 100], vec![0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7]],
+             vec![(0, true, 60)]),
+            // 0xFF is a system reset on a midi port, not a meta event
+            (vec![0xFF, 0xFE], vec![vec![0xFF], vec![0xFE]], vec![]),
+            // controllers and pitch bends with data in the keyboard range
+            (vec![0xB0, 64, 127, 0xE0, 60, 64], vec![vec![0xB0, 64, 127], vec![0xE0, 60, 64]], vec![]),
+            // keys out of the keyboard are only heard
+            (vec![0x90, 10, 100], vec![vec![0x90, 10, 100]], vec![]),
+            // stray data bytes and truncated messages are discarded
+            (vec![60, 100, 0x90, 60, 100, 0x80, 60], vec![vec![0x90, 60, 100]], vec![(0, true, 60)]),
+        ];
+
+        for (stream, messages, keys) in table {
+            let music_event = midi_to_music_events(&stream);
+            assert_eq!(music_event.midi_messages, messages, "stream {:?}", stream);
+            let key_events: Vec<(u8, bool, u8)> = music_event.key_events
+                .iter()
+                .map(|x| match x.data {
+                         KeyData::Pressed(pitch, _) => (x.channel, true, pitch),
+                         KeyData::Released(pitch) => (x.channel, false, pitch),
+                     })
+                .collect();
+            assert_eq!(key_events, keys, "stream {:?}", stream);
+        }
+    }
+}